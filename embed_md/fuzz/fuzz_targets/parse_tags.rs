@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|source: &str| {
+    let (tokens, _) = embed_md::tokenize(source);
+    let end = tokens.last().map_or(0, |token| token.span.end);
+    assert_eq!(end, source.len());
+    let (tags, diagnostics) = embed_md::parse_tags(source);
+    for span in tags.iter().map(|t| t.span).chain(diagnostics.iter().map(|d| d.span)) {
+        assert!(source.is_char_boundary(span.start) && source.is_char_boundary(span.end));
+    }
+});
@@ -1,101 +1,159 @@
-use std::collections::HashMap;
-use std::env::current_dir;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
-use std::io::Write;
 use std::ops::Range;
-use std::process::{Command, Stdio};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use base64::engine::general_purpose;
 use base64::Engine;
 use regex::Regex;
 use sha2::{Digest, Sha256};
 
-use embed_md_derive::RangeFn;
-use embed_md_traits::FunctionTag;
-use embed_md_traits::Rangeable;
+use embed_md_derive::{EmbedParams, ParamValue, RangeFn};
+use embed_md_traits::{
+    format_duration, Context, EmbedError, EmbedParams, ErrorKind, FunctionTag, RunMode,
+};
 
-use crate::helpers::extract_map;
+use crate::config::Config;
+use crate::functions::FunctionArgs;
+use crate::languages::{Interpreter, LanguageRegistry, RunSettings, LANGUAGES_FILE};
+use crate::parser::{parse_directives, quote_value};
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum FunctionType {
-    Identity(Identity),
-    ExecCode(ExecCode),
+#[derive(RangeFn, Debug, Clone, PartialEq)]
+pub struct ExecCode {
+    id: String,
+    params: ExecParams,
+    is_end: bool,
+    range: Range<usize>,
 }
 
-impl FunctionType {
-    pub fn from(
-        id: String,
-        function: String,
-        params: HashMap<String, String>,
-        is_end: bool,
-        range: Range<usize>,
-    ) -> FunctionType {
-        match function.as_str() {
-            "identity" => FunctionType::Identity(Identity { id, is_end, range }),
-            "exec-code" => FunctionType::ExecCode(ExecCode {
-                id,
-                is_end,
-                params,
-                range,
-            }),
-            _ => panic!("Not a known function",),
-        }
+impl ExecCode {
+    /// Fails if the tag has a param `exec-code` doesn't know or one that can't be read
+    pub fn new(args: FunctionArgs) -> Result<Self, EmbedError> {
+        Ok(ExecCode {
+            params: ExecParams::from_params(&args.params).map_err(|e| e.with_id(&args.id))?,
+            id: args.id,
+            is_end: args.is_end,
+            range: args.range,
+        })
     }
 }
 
-impl Rangeable for FunctionType {
-    fn range(&self) -> Range<usize> {
-        match self {
-            FunctionType::Identity(i) => i.range(),
-            FunctionType::ExecCode(i) => i.range(),
-        }
+/// The params of `exec-code`
+#[derive(EmbedParams, Debug, Clone, PartialEq)]
+pub struct ExecParams {
+    /// The language to run the code block with
+    #[param(default = "shell")]
+    pub lang: String,
+    /// The language the result block is marked as, none leaves the result out
+    pub o_lang: Option<String>,
+    /// A command to run the code with instead of the language's
+    pub interpreter: Option<String>,
+    /// How long the code may run for, 0 turns off a default timeout
+    pub timeout: Option<Duration>,
+    /// When the code runs again, without it the code runs every time
+    pub cache: Option<Cache>,
+    /// How old a result may get before it runs again
+    pub ttl: Option<Duration>,
+    /// What happens to what the code writes to stderr
+    #[param(default = "ignore")]
+    pub stderr: Stderr,
+    /// Fail the run when the code exits with an error
+    #[param(default = "false")]
+    pub fail_on_error: bool,
+    /// Globs of files whose content is part of the hash, relative to where the code runs
+    pub depends_on: Option<Vec<String>>,
+    /// The directory the code runs from
+    pub exec_path: Option<PathBuf>,
+    /// The directory the code runs from, relative to the document
+    pub r_exec_path: Option<PathBuf>,
+    /// The directory outputs are kept in
+    pub out_dir: Option<PathBuf>,
+    /// Variables set for the code, as `KEY=value` pairs separated by commas
+    pub env: Option<String>,
+}
+
+/// When `exec-code` runs again
+#[derive(ParamValue, Debug, Clone, Copy, PartialEq)]
+pub enum Cache {
+    /// Only when there's no result yet
+    Always,
+    /// When the code, its env, its dependencies or an output it reads change
+    Hash,
+    /// Once the result is older than the ttl
+    Time,
+    /// Every time, the same as no cache
+    Never,
+}
+
+impl FunctionTag for ExecCode {
+    fn transform(&self, text: String, context: &Context) -> Result<String, EmbedError> {
+        exec_code(text.as_str(), &self.params, &self.id, context).map_err(|e| e.with_id(&self.id))
     }
 
-    fn id(&self) -> String {
-        match self {
-            FunctionType::Identity(i) => i.id(),
-            FunctionType::ExecCode(i) => i.id(),
-        }
+    fn dependencies(&self, text: &str) -> Vec<String> {
+        Regex::new(CODE_BLOCK)
+            .unwrap()
+            .captures(text)
+            .map(|c| references(&c[2]))
+            .unwrap_or_default()
     }
-}
 
-impl FunctionTag for FunctionType {
-    fn transform(&self, text: String) -> String {
-        match self {
-            FunctionType::Identity(i) => i.transform(text),
-            FunctionType::ExecCode(i) => i.transform(text),
-        }
+    /// The globs given in `depends_on`, relative to the directory the code runs from
+    fn depends_on(&self, context: &Context) -> Vec<String> {
+        let dir = run_dir(
+            self.params.exec_path.as_deref(),
+            self.params.r_exec_path.as_deref(),
+            context,
+        );
+        let dir = fs::canonicalize(&dir).unwrap_or(PathBuf::from(dir));
+        self.params
+            .depends_on
+            .iter()
+            .flatten()
+            .map(|pattern| {
+                format!(
+                    "{}/{}",
+                    glob::Pattern::escape(&dir.to_string_lossy()),
+                    pattern
+                )
+            })
+            .collect()
     }
-}
 
-#[derive(RangeFn, Debug, Clone, PartialEq)]
-pub struct ExecCode {
-    id: String,
-    params: HashMap<String, String>,
-    is_end: bool,
-    range: Range<usize>,
+    fn stale_reasons(&self, before: &str, after: &str) -> Vec<String> {
+        stale_reasons(before, after)
+    }
 }
 
-impl FunctionTag for ExecCode {
-    fn transform(&self, text: String) -> String {
-        exec_code(text.as_str(), &self.params).unwrap()
+/// The first fenced code block, the whole block in group 1 and its code in group 2
+const CODE_BLOCK: &str = "(```.*?\n((.*\n)*?)```)(?s)";
+
+/// Matches `$$id$$`, which is replaced with the path to the output of the embed with that id
+const REFERENCE: &str = r"\$\$(.*?)\$\$";
+
+/// The ids referenced in code, in the order they first appear
+fn references(code: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for cap in Regex::new(REFERENCE).unwrap().captures_iter(code) {
+        if !ids.contains(&cap[1].to_string()) {
+            ids.push(cap[1].to_string());
+        }
     }
+    ids
 }
 
-fn exec_code(text: &str, params: &HashMap<String, String>) -> Result<String, String> {
-    let re = Regex::new("(```.*?\n((.*\n)*?)```)(?s)").unwrap();
-    let meta_re = Regex::new(r#"<!--embed-meta +((\w*=".*?":? )*)? ?(/?)-->\n"#).unwrap();
+fn exec_code(
+    text: &str,
+    params: &ExecParams,
+    id: &str,
+    context: &Context,
+) -> Result<String, EmbedError> {
+    let re = Regex::new(CODE_BLOCK).unwrap();
     let result_header_re = Regex::new(r"<!-- result -->\n").unwrap();
-    let meta_option = meta_re.captures(text);
-    let meta = match meta_option {
-        None => HashMap::new(),
-        Some(t) => extract_map(&t[1]),
-    };
+    let meta = read_meta(text);
     let (to_exec, with_block, remaining) = match re.captures(text) {
-        None => {
-            panic!("No code block found in text {:?}", text)
-        }
+        None => return Err(EmbedError::new(ErrorKind::MissingCodeBlock)),
         Some(t) => (
             t.get(2).unwrap().as_str(),
             t.get(1).unwrap().as_str(),
@@ -112,148 +170,420 @@ fn exec_code(text: &str, params: &HashMap<String, String>) -> Result<String, Str
         end: result_start,
     };
 
-    let default_path = "./".to_string();
-    let file_loc = params.get("exec_location").unwrap_or(&default_path);
-    let mut exec_loc = current_dir().unwrap();
-    exec_loc.push(file_loc);
-    let file_name = params.get("file_name").unwrap();
-    exec_loc.push(file_name);
-    let mut wrapper = Sha256::new();
-    wrapper.update(
-        fs::canonicalize(exec_loc)
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .as_bytes(),
-    );
-    let output_file_hash = wrapper.finalize();
-    let output_file_hash_b64 = general_purpose::URL_SAFE_NO_PAD.encode(output_file_hash.as_slice());
-    let binding = "~/.embed_md".to_string();
-    let out_dir_path = params.get("out_dir").unwrap_or(&binding);
-    let out_dir = shellexpand::tilde(out_dir_path).to_string();
+    let out_file = |id: &str| context.outputs.path(id).to_string_lossy().to_string();
     // Check if outdir exists if it doesn't create it
-    match fs::metadata(&out_dir) {
+    let id_out = context.outputs.path(id);
+    let out_dir = id_out.parent().unwrap_or(Path::new("."));
+    match fs::metadata(out_dir) {
         Ok(_) => (),
         // Make this resilient to tests running in parallel in CI
-        Err(_) => match fs::create_dir(&out_dir) {
+        Err(_) => match fs::create_dir(out_dir) {
             Ok(_) => (),
             Err(e) => match e.kind() {
                 std::io::ErrorKind::AlreadyExists => (),
-                _ => panic!("Error creating directory: {}", e),
+                _ => return Err(e.into()),
             },
         },
     }
 
-    let id_match = Regex::new(r"\$\$(.*?)\$\$").unwrap();
-    let exec_replaced = id_match.replace_all(
-        to_exec,
-        format!("{}/{}_$1.out", out_dir, output_file_hash_b64),
+    let id_match = Regex::new(REFERENCE).unwrap();
+    let exec_replaced = id_match.replace_all(to_exec, out_file("$1"));
+    let env = &context.env;
+    let run_dir = run_dir(
+        params.exec_path.as_deref(),
+        params.r_exec_path.as_deref(),
+        context,
     );
+    let dependencies = match &params.depends_on {
+        Some(patterns) => file_dependencies(&run_dir, patterns)?,
+        None => Vec::new(),
+    };
     let mut wrapper = Sha256::new();
     wrapper.update(exec_replaced.as_bytes());
+    // Left out when empty so hashes from before env was supported stay valid
+    for (key, value) in env {
+        wrapper.update(format!("\0{}={}", key, value).as_bytes());
+    }
+    for (path, hash) in &dependencies {
+        wrapper.update(format!("\0{}={}", path, hash).as_bytes());
+    }
+    // What upstream embeds last wrote, so a change in their output makes this one stale too
+    for id in references(to_exec) {
+        if let Ok(output) = fs::read(out_file(&id)) {
+            wrapper.update(format!("\0{}=", id).as_bytes());
+            wrapper.update(Sha256::digest(output));
+        }
+    }
     let result = wrapper.finalize();
     let b64 = general_purpose::STANDARD_NO_PAD.encode(result.as_slice());
+    let depends_on = format_dependencies(&dependencies);
 
     let existing_hash = meta.get("hash").map_or("", String::as_str);
     let last_run = meta.get("last_run").map_or("0", String::as_str);
-    let rerun = match params.get("cache") {
-        Some(x) => match x.as_str() {
-            "always" => false,
-            "hash" => b64 != *existing_hash,
-            "time" => {
-                todo!("Need to implement time")
-            }
-            _ => true,
-        },
-        _ => true,
+    let expired = || -> Result<bool, EmbedError> {
+        let ttl = match params.ttl {
+            Some(ttl) => ttl,
+            None => return Ok(false),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis();
+        Ok(now.saturating_sub(last_run.parse().unwrap_or(0)) > ttl.as_millis())
     };
+    let stale = match params.cache {
+        // Without a hash the code has never run, so there's no result to keep
+        Some(Cache::Always) => existing_hash.is_empty(),
+        // A ttl alongside hash caching also reruns once the result is too old
+        Some(Cache::Hash) => b64 != *existing_hash || expired()?,
+        Some(Cache::Time) if params.ttl.is_some() => expired()?,
+        Some(Cache::Time) => {
+            return Err(EmbedError::new(ErrorKind::InvalidParam(
+                "ttl".to_string(),
+                "required when cache=\"time\"".to_string(),
+            )))
+        }
+        Some(Cache::Never) | None => true,
+    };
+    let rerun = stale || context.force;
+    let check = context.mode == RunMode::Check;
+    // Checks and dry runs leave the disk as it was, neither the outputs later embeds read nor the
+    // timestamp later cache decisions read may change
+    let persist = context.mode == RunMode::Write;
+
+    // A cached result keeps the exit code of the run that produced it
+    let mut cached_meta = vec![("hash", b64.as_str()), ("last_run", last_run)];
+    if params.depends_on.is_some() {
+        cached_meta.push(("depends_on", &depends_on));
+    }
+    if let Some(exit_code) = meta.get("exit_code") {
+        cached_meta.push(("exit_code", exit_code));
+    }
 
     match rerun {
+        // The hash alone says this is stale, no need to execute anything
+        true if check && params.cache == Some(Cache::Hash) && b64 != *existing_hash => {
+            Ok(meta_line(&cached_meta) + with_block + &text[remaining])
+        }
         true => {
-            let lang = params.get("lang").map_or("shell", String::as_str);
-            let start = SystemTime::now();
-            let since_the_epoch = start
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards");
-            let exec_path = params.get("exec_path");
-            let r_exec_path = params.get("r_exec_path");
-            let path = match exec_path {
-                Some(path) => path.to_string(),
-                None => match r_exec_path {
-                    Some(path) => format!("{}/{}", file_loc, path),
-                    None => file_loc.to_string(),
+            let lang = params.lang.as_str();
+            let run_at = match persist {
+                false => last_run.to_string(),
+                true => SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis()
+                    .to_string(),
+            };
+            let stderr = params.stderr;
+            let interpreter = match &params.interpreter {
+                Some(command_line) => Interpreter::parse(command_line).ok_or_else(|| {
+                    EmbedError::new(ErrorKind::InvalidParam(
+                        "interpreter".to_string(),
+                        "no command given".to_string(),
+                    ))
+                })?,
+                None => registry(context)?.get(lang).cloned().ok_or_else(|| {
+                    EmbedError::exec(format!(
+                        "language '{}' is not supported, add it to {} or set interpreter",
+                        lang, LANGUAGES_FILE
+                    ))
+                })?,
+            };
+            ensure_run_dir(
+                &run_dir,
+                params.exec_path.as_deref(),
+                params.r_exec_path.as_deref(),
+            )?;
+            let timeout = params.timeout.filter(|t| !t.is_zero());
+            let execution = interpreter.run(
+                &exec_replaced,
+                &RunSettings {
+                    dir: run_dir,
+                    capture_stderr: stderr != Stderr::Ignore,
+                    timeout,
+                    env: env.clone(),
                 },
+            )?;
+            let output = execution.output;
+            let timed_out_message = format!(
+                "timed out after {}",
+                timeout.map(format_duration).unwrap_or_default()
+            );
+            let timed_out = match execution.timed_out {
+                true => format!("<!-- {} -->\n", timed_out_message),
+                false => String::new(),
             };
-            let exec_lang = match lang {
-                "shell" | "sh" => "sh",
-                "zsh" => "zsh",
-                "bash" => "bash",
-                s if s.starts_with("python") => "python3",
-                _ => {
-                    todo!("Need to implement other languages: {}", lang)
-                }
+            // Killed by a signal there is no code to record. A zero exit code is left out so the
+            // embed-meta of code that succeeds reads the same as it did before exit codes were
+            // recorded, rewriting every such embed and reporting it stale with --check otherwise.
+            let exit_code = match output.status.code() {
+                _ if execution.timed_out => Some("timeout".to_string()),
+                Some(0) => None,
+                Some(code) => Some(code.to_string()),
+                None => Some("signal".to_string()),
             };
-            let mut child = Command::new(exec_lang)
-                .current_dir(shellexpand::tilde(path.as_str()).to_string())
-                .stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .spawn()
-                .expect("Failed to start command");
-
-            {
-                let stdin = child.stdin.as_mut().expect("Failed to open stdin");
-                stdin
-                    .write_all(exec_replaced.as_bytes())
-                    .expect("Failed to write to stdin");
+            if let Some(code) = &exit_code {
+                if params.fail_on_error {
+                    // A failed build's diagnostics are its output, there's no stderr to show
+                    let reason = match output.stderr.is_empty() {
+                        true => &output.stdout,
+                        false => &output.stderr,
+                    };
+                    return Err(EmbedError::exec(match execution.timed_out {
+                        true => timed_out_message,
+                        false => format!(
+                            "exited with status {}: {}",
+                            code,
+                            String::from_utf8_lossy(reason).trim()
+                        ),
+                    }));
+                }
             }
+            let stdout = match stderr {
+                Stderr::Merge => [output.stdout, output.stderr.clone()].concat(),
+                _ => output.stdout,
+            };
 
-            let output = child.wait_with_output().expect("Failed to read stdout");
-
-            let id_out = format!(
-                "{}/{}_{}.out",
-                out_dir,
-                output_file_hash_b64,
-                params.get("exec_id").unwrap()
-            );
-            match fs::write(id_out.clone(), &output.stdout) {
-                Ok(_) => (),
-                Err(e) => panic!("Error writing to file: {}, {}", id_out, e),
+            if persist {
+                fs::write(&id_out, &stdout).map_err(|e| {
+                    EmbedError::exec(format!("error writing to file {}: {}", id_out.display(), e))
+                })?;
             }
 
-            let maybe_new_line = match output.stdout.ends_with(&[10]) {
-                true => "",
-                false => "\n",
-            };
-            match params.get("o_lang") {
-                Some(x) if x == "none" => Ok(format!(
-                    "<!--embed-meta hash=\"{}\": last_run=\"{}\" -->\n",
-                    b64,
-                    since_the_epoch.as_millis()
-                ) + with_block
-                    + &text[result_header]
-                    + "\n<!-- result -->\n"),
-                _ => Ok(format!(
-                    "<!--embed-meta hash=\"{}\": last_run=\"{}\" -->\n",
-                    b64,
-                    since_the_epoch.as_millis()
-                ) + with_block
+            let mut run_meta = vec![("hash", b64.as_str()), ("last_run", run_at.as_str())];
+            if params.depends_on.is_some() {
+                run_meta.push(("depends_on", &depends_on));
+            }
+            if let Some(code) = &exit_code {
+                run_meta.push(("exit_code", code));
+            }
+            match params.o_lang.as_deref() {
+                Some("none") => Ok(meta_line(&run_meta)
+                    + with_block
                     + &text[result_header]
-                    + "\n<!-- result -->\n```"
-                    + params.get("o_lang").map_or("", String::as_str)
-                    + "\n"
-                    + std::str::from_utf8(&output.stdout).unwrap()
-                    + maybe_new_line
-                    + "```\n"),
+                    + "\n<!-- result -->\n"
+                    + &timed_out),
+                o_lang => {
+                    let o_lang = o_lang.unwrap_or_default();
+                    let mut result = meta_line(&run_meta)
+                        + with_block
+                        + &text[result_header]
+                        + "\n<!-- result -->\n"
+                        + &fenced(o_lang, &stdout)
+                        + &timed_out;
+                    if stderr == Stderr::Separate && !output.stderr.is_empty() {
+                        result = result + "<!-- stderr -->\n" + &fenced("", &output.stderr);
+                    }
+                    Ok(result)
+                }
             }
         }
-        false => Ok(format!(
-            "<!--embed-meta hash=\"{}\": last_run=\"{}\" -->\n",
-            b64, last_run
-        ) + with_block
-            + &text[remaining]),
+        false => Ok(meta_line(&cached_meta) + with_block + &text[remaining]),
     }
 }
 
+/// The directory code runs from, relative to the current directory unless absolute
+pub(crate) fn run_dir(
+    exec_path: Option<&Path>,
+    r_exec_path: Option<&Path>,
+    context: &Context,
+) -> String {
+    let root = context.root.to_string_lossy();
+    let file_loc = match root.is_empty() {
+        true => "./",
+        false => &root,
+    };
+    let run_dir = match exec_path {
+        Some(path) => path.to_string_lossy().to_string(),
+        None => match r_exec_path {
+            Some(path) => format!("{}/{}", file_loc, path.display()),
+            None => file_loc.to_string(),
+        },
+    };
+    shellexpand::tilde(run_dir.as_str()).to_string()
+}
+
+/// Fails unless `dir` from `run_dir` is a directory, naming the param that gave it. Spawning in a
+/// missing directory fails too, but the error names the interpreter rather than the directory.
+pub(crate) fn ensure_run_dir(
+    dir: &str,
+    exec_path: Option<&Path>,
+    r_exec_path: Option<&Path>,
+) -> Result<(), EmbedError> {
+    let problem = match fs::metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => return Ok(()),
+        Ok(_) => "is not a directory",
+        Err(_) => "does not exist",
+    };
+    let source = match (exec_path, r_exec_path) {
+        (Some(_), _) => "exec_path",
+        (None, Some(_)) => "r_exec_path",
+        (None, None) => "directory",
+    };
+    Err(EmbedError::exec(format!(
+        "{} '{}' {}",
+        source, dir, problem
+    )))
+}
+
+/// The files matched by comma separated globs relative to `dir`, each with a short hash of its
+/// content, sorted by path
+fn file_dependencies(dir: &str, patterns: &[String]) -> Result<Vec<(String, String)>, EmbedError> {
+    let invalid = |e: String| EmbedError::new(ErrorKind::InvalidParam("depends_on".to_string(), e));
+    let mut files = BTreeMap::new();
+    for pattern in patterns {
+        let full = format!("{}/{}", glob::Pattern::escape(dir), pattern);
+        for path in glob::glob(&full).map_err(|e| invalid(format!("'{}' {}", pattern, e)))? {
+            let path = path.map_err(|e| EmbedError::from(std::io::Error::from(e)))?;
+            if !path.is_file() {
+                continue;
+            }
+            let hash = general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(fs::read(&path)?));
+            let relative = path.strip_prefix(dir).unwrap_or(&path);
+            files.insert(
+                relative.to_string_lossy().to_string(),
+                hash[..DEPENDENCY_HASH_LEN].to_string(),
+            );
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Enough of a file's hash to tell versions apart in `embed-meta`
+const DEPENDENCY_HASH_LEN: usize = 12;
+
+/// Writes dependencies for `embed-meta` as `path=hash` pairs separated by commas. A `,` or `\` in a
+/// path is escaped with a `\`, the hash is url safe base64 so it never has either, or an `=`.
+fn format_dependencies(dependencies: &[(String, String)]) -> String {
+    dependencies
+        .iter()
+        .map(|(path, hash)| {
+            let path = path.replace('\\', "\\\\").replace(',', "\\,");
+            format!("{}={}", path, hash)
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+/// Reads dependencies written by `format_dependencies`, skipping any pair it can't make sense of
+fn parse_dependencies(list: &str) -> BTreeMap<String, String> {
+    let mut pairs = Vec::new();
+    let mut pair = String::new();
+    let mut chars = list.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => pair.extend(chars.next()),
+            ',' => pairs.push(std::mem::take(&mut pair)),
+            c => pair.push(c),
+        }
+    }
+    pairs.push(pair);
+    pairs
+        .iter()
+        .filter_map(|pair| pair.rsplit_once('='))
+        .filter(|(path, _)| !path.is_empty())
+        .map(|(path, hash)| (path.to_string(), hash.to_string()))
+        .collect()
+}
+
+/// Explains why an embed is stale given its text before and after running it. Dependencies that
+/// changed are named, otherwise the reason is as specific as the `embed-meta` allows.
+fn stale_reasons(before: &str, after: &str) -> Vec<String> {
+    let dependencies = |meta: &HashMap<String, String>| {
+        meta.get("depends_on")
+            .map(|list| parse_dependencies(list))
+            .unwrap_or_default()
+    };
+    let (before, after) = (read_meta(before), read_meta(after));
+    if before.is_empty() {
+        return vec!["never run".to_string()];
+    }
+    let (old, new) = (dependencies(&before), dependencies(&after));
+    let mut reasons: Vec<String> = new
+        .iter()
+        .filter_map(|(path, hash)| match old.get(path) {
+            None => Some(format!("{} was added", path)),
+            Some(old_hash) if old_hash != hash => Some(format!("{} changed", path)),
+            Some(_) => None,
+        })
+        .collect();
+    reasons.extend(
+        old.keys()
+            .filter(|path| !new.contains_key(*path))
+            .map(|path| format!("{} was removed", path)),
+    );
+    if reasons.is_empty() {
+        reasons.push(match before.get("hash") == after.get("hash") {
+            true => "output changed".to_string(),
+            false => "code, env or an embed it reads changed".to_string(),
+        });
+    }
+    reasons
+}
+
+/// The languages available to an embed, including those from its project config
+fn registry(context: &Context) -> Result<LanguageRegistry, EmbedError> {
+    let mut registry = LanguageRegistry::load()?;
+    if let Some(config) = context.config::<Config>() {
+        registry.extend(config.languages.clone());
+    }
+    Ok(registry)
+}
+
+/// What happens to what a command writes to stderr
+#[derive(ParamValue, Debug, Clone, Copy, PartialEq)]
+pub enum Stderr {
+    /// Left to go to the terminal
+    Ignore,
+    /// Appended to stdout in the result
+    Merge,
+    /// Embedded in its own block after the result
+    Separate,
+}
+
+/// The `embed-meta` of an embed, one that can't be read is treated as missing so the embed runs
+/// again and rewrites it
+pub(crate) fn read_meta(text: &str) -> HashMap<String, String> {
+    parse_directives(text, "meta")
+        .0
+        .into_iter()
+        .next()
+        .map(|meta| {
+            meta.attributes
+                .into_iter()
+                .map(|attribute| (attribute.key, attribute.value))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Renders the `<!--embed-meta ... -->` line from its key value pairs
+pub(crate) fn meta_line(entries: &[(&str, &str)]) -> String {
+    format!(
+        "<!--embed-meta {} -->\n",
+        entries
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, quote_value(value)))
+            .collect::<Vec<String>>()
+            .join(": ")
+    )
+}
+
+/// Wraps output in a code block, adding the final new line if the output doesn't end with one
+fn fenced(lang: &str, output: &[u8]) -> String {
+    let maybe_new_line = match output.ends_with(&[10]) {
+        true => "",
+        false => "\n",
+    };
+    format!(
+        "```{}\n{}{}```\n",
+        lang,
+        String::from_utf8_lossy(output),
+        maybe_new_line
+    )
+}
+
 #[derive(RangeFn, Debug, Clone, PartialEq)]
 pub struct Identity {
     id: String,
@@ -267,14 +597,44 @@ impl Identity {
     }
 }
 impl FunctionTag for Identity {
-    fn transform(&self, text: String) -> String {
-        text.clone()
+    fn transform(&self, text: String, _context: &Context) -> Result<String, EmbedError> {
+        Ok(text)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use crate::outputs::OutputDir;
+    use std::sync::Arc;
+
+    /// The context of an embed in `Cargo.toml`, keeping outputs in the test directory
+    pub(crate) fn context(mode: RunMode) -> Context {
+        let source = Path::new("Cargo.toml");
+        Context {
+            source: Some(source.to_path_buf()),
+            root: PathBuf::from("."),
+            config: Arc::new(Config::default()),
+            mode,
+            force: false,
+            env: BTreeMap::new(),
+            outputs: Arc::new(OutputDir::new(
+                "../test_out_dir",
+                Some(source),
+                Path::new("."),
+            )),
+        }
+    }
+
+    /// Runs `exec-code` with params as they would come from a tag
+    fn exec(
+        text: &str,
+        params: &HashMap<String, String>,
+        id: &str,
+        context: &Context,
+    ) -> Result<String, EmbedError> {
+        exec_code(text, &ExecParams::from_params(params)?, id, context)
+    }
 
     const EXEC_RESULT: &str = r#"<!--embed-meta hash="ZtPq82dXLdZblrj0VuVOPyjQqP9cuvsoaZV7p49AbR8": last_run="1111" -->
 ```shell
@@ -313,16 +673,16 @@ another
 
     #[test]
     fn test_exec_code() {
-        let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert("exec_id".to_string(), "test_exec_code".to_string());
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let params = HashMap::new();
+        let id = "test_exec_code";
+        let result = exec(
             r#"```shell
 echo "test"; echo "another"
 ```
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
         assert!(result.is_ok());
         let re = Regex::new(r#"(?<ts>last_run=")(\d+)""#).unwrap();
@@ -334,11 +694,9 @@ echo "test"; echo "another"
 
     #[test]
     fn test_exec_code_existing() {
-        let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert("exec_id".to_string(), "test_exec_code_existing".to_string());
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let params = HashMap::new();
+        let id = "test_exec_code_existing";
+        let result = exec(
             r#"```shell
 echo "test"; echo "another"
 ```
@@ -349,6 +707,8 @@ another
 ```
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
         assert!(result.is_ok());
         let re = Regex::new(r#"(?<ts>last_run=")(\d+)""#).unwrap();
@@ -360,20 +720,17 @@ another
 
     #[test]
     fn test_exec_code_existing_technically_legal() {
-        let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert(
-            "exec_id".to_string(),
-            "test_exec_code_existing_technically_legal".to_string(),
-        );
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let params = HashMap::new();
+        let id = "test_exec_code_existing_technically_legal";
+        let result = exec(
             r#"```shell
 echo "test"; echo "another"
 ```
 <!-- result -->
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
 
         assert!(result.is_ok());
@@ -386,20 +743,17 @@ echo "test"; echo "another"
 
     #[test]
     fn test_exec_code_header_no_result() {
-        let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert(
-            "exec_id".to_string(),
-            "test_exec_code_header_no_result".to_string(),
-        );
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let params = HashMap::new();
+        let id = "test_exec_code_header_no_result";
+        let result = exec(
             r#"```shell
 echo "test"; echo "another"
 ```
 something something
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
 
         assert!(result.is_ok());
@@ -412,14 +766,9 @@ something something
 
     #[test]
     fn test_exec_code_existing_with_result_header() {
-        let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert(
-            "exec_id".to_string(),
-            "test_exec_code_existing_with_result_header".to_string(),
-        );
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let params = HashMap::new();
+        let id = "test_exec_code_existing_with_result_header";
+        let result = exec(
             r#"#
                 ```shell
 echo "test"; echo "another"
@@ -432,6 +781,8 @@ another
 ```
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
         assert!(result.is_ok());
         let re = Regex::new(r#"(?<ts>last_run=")(\d+)""#).unwrap();
@@ -443,20 +794,17 @@ another
 
     #[test]
     fn test_exec_code_multi_line() {
-        let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert(
-            "exec_id".to_string(),
-            "test_exec_code_multi_line".to_string(),
-        );
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let params = HashMap::new();
+        let id = "test_exec_code_multi_line";
+        let result = exec(
             r#"```shell
 echo "test"
 echo "another"
 ```
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
         assert!(result.is_ok());
         let re = Regex::new(r#"(?<ts>last_run=")(\d+)""#).unwrap();
@@ -469,14 +817,9 @@ echo "another"
     #[test]
     fn test_exec_code_cached_pre_result_text() {
         let mut params = HashMap::new();
-        params.insert("file_name".to_string(), "Cargo.toml".to_string());
-        params.insert(
-            "exec_id".to_string(),
-            "test_exec_code_existing_with_result_header".to_string(),
-        );
+        let id = "test_exec_code_existing_with_result_header";
         params.insert("cache".to_string(), "always".to_string());
-        params.insert("out_dir".to_string(), "../test_out_dir".to_string());
-        let result = exec_code(
+        let result = exec(
             r#"#
                 ```shell
 echo "test"; echo "another"
@@ -489,6 +832,8 @@ another
 ```
 "#,
             &params,
+            id,
+            &context(RunMode::Write),
         );
         assert!(result.is_ok());
         let re = Regex::new(r#"(?<ts>last_run=")(\d+)""#).unwrap();
@@ -497,4 +842,529 @@ another
         // let static_time = result.unwrap().as_str().replace(r#"last_run=""#, "last_run=\"1697141890682\"");
         assert_eq!(static_time, EXEC_RESULT_WITH_RESULT_HEADER.to_string())
     }
+
+    #[test]
+    fn test_exec_code_check_hash_does_not_execute() {
+        let mut params = HashMap::new();
+        let id = "test_exec_code_check_hash_does_not_execute";
+        params.insert("cache".to_string(), "hash".to_string());
+        let result = exec(
+            r#"<!--embed-meta hash="outdated": last_run="1111" -->
+```shell
+echo "test"; echo "another"
+```
+<!-- result -->
+```
+stale
+```
+"#,
+            &params,
+            id,
+            &context(RunMode::Check),
+        );
+        assert_eq!(
+            result.unwrap(),
+            r#"<!--embed-meta hash="ZtPq82dXLdZblrj0VuVOPyjQqP9cuvsoaZV7p49AbR8": last_run="1111" -->
+```shell
+echo "test"; echo "another"
+```
+<!-- result -->
+```
+stale
+```
+"#
+        )
+    }
+
+    #[test]
+    fn test_exec_code_check_keeps_last_run() {
+        let params = HashMap::new();
+        let id = "test_exec_code_check_keeps_last_run";
+        let result = exec(EXEC_RESULT, &params, id, &context(RunMode::Check));
+        assert_eq!(result.unwrap(), EXEC_RESULT)
+    }
+
+    #[test]
+    fn test_exec_code_dry_run_leaves_disk() {
+        let params = HashMap::new();
+        let id = "test_exec_code_dry_run_leaves_disk";
+        let context = context(RunMode::DryRun);
+        let output = context.outputs.path(id);
+        let _ = fs::remove_file(&output);
+        let text = EXEC_RESULT.replace("test\nanother\n```\n", "stale\n```\n");
+        let result = exec(&text, &params, id, &context).unwrap();
+        assert_eq!(result, EXEC_RESULT, "runs the code but keeps last_run");
+        assert!(!output.exists());
+    }
+
+    fn time_cached(ttl: &str, last_run: u128) -> Result<String, EmbedError> {
+        let mut params = HashMap::new();
+        let id = "test_exec_code_time";
+        params.insert("cache".to_string(), "time".to_string());
+        params.insert("ttl".to_string(), ttl.to_string());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        exec(
+            &format!(
+                r#"<!--embed-meta hash="ZtPq82dXLdZblrj0VuVOPyjQqP9cuvsoaZV7p49AbR8": last_run="{}" -->
+```shell
+echo "test"; echo "another"
+```
+<!-- result -->
+```
+cached
+```
+"#,
+                now - last_run
+            ),
+            &params,
+            id,
+            &context(RunMode::Write),
+        )
+    }
+
+    #[test]
+    fn test_exec_code_time_fresh() {
+        let result = time_cached("1h", 30 * 60 * 1000).unwrap();
+        assert!(result.contains("cached"));
+    }
+
+    #[test]
+    fn test_exec_code_time_expired() {
+        let result = time_cached("30m", 31 * 60 * 1000).unwrap();
+        assert!(!result.contains("cached"));
+        assert!(result.contains("test\nanother\n"));
+    }
+
+    #[test]
+    fn test_exec_code_time_invalid_ttl() {
+        let error = time_cached("soon", 0).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::InvalidParam(ref p, _) if p == "ttl"));
+    }
+
+    #[test]
+    fn test_exec_code_hash_with_expired_ttl() {
+        let mut params = HashMap::new();
+        let id = "test_exec_code_hash_with_expired_ttl";
+        params.insert("cache".to_string(), "hash".to_string());
+        params.insert("ttl".to_string(), "1d".to_string());
+        let result = exec(
+            r#"<!--embed-meta hash="ZtPq82dXLdZblrj0VuVOPyjQqP9cuvsoaZV7p49AbR8": last_run="1111" -->
+```shell
+echo "test"; echo "another"
+```
+<!-- result -->
+```
+cached
+```
+"#,
+            &params,
+            id,
+            &context(RunMode::Write),
+        );
+        assert!(!result.unwrap().contains("cached"));
+    }
+
+    #[test]
+    fn test_exec_code_interpreter_param() {
+        let mut params = HashMap::new();
+        let id = "test_exec_code_interpreter_param";
+        params.insert("lang".to_string(), "made-up".to_string());
+        params.insert("interpreter".to_string(), "sh -s".to_string());
+        let result = exec(
+            r#"```made-up
+echo "test"; echo "another"
+```
+"#,
+            &params,
+            id,
+            &context(RunMode::Write),
+        );
+        assert!(result.unwrap().contains("test\nanother\n"));
+    }
+
+    #[test]
+    fn test_exec_code_config_languages() {
+        let id = "test_exec_code_config_languages";
+        let mut params = HashMap::new();
+        params.insert("lang".to_string(), "made-up".to_string());
+        let mut config = Config::default();
+        config
+            .languages
+            .insert("made-up".to_string(), Interpreter::new("sh", &[]));
+        let context = Context {
+            config: Arc::new(config),
+            ..context(RunMode::Write)
+        };
+        let result = exec(
+            r#"```made-up
+echo "from config"
+```
+"#,
+            &params,
+            id,
+            &context,
+        );
+        assert!(result.unwrap().contains("from config\n"));
+    }
+
+    #[test]
+    fn test_exec_code_unknown_language() {
+        let mut params = HashMap::new();
+        let id = "test_exec_code_unknown_language";
+        params.insert("lang".to_string(), "made-up".to_string());
+        let result = exec(
+            r#"```made-up
+echo "test"
+```
+"#,
+            &params,
+            id,
+            &context(RunMode::Write),
+        );
+        assert!(matches!(*result.unwrap_err().kind, ErrorKind::Exec(_)));
+    }
+
+    const FAILING: &str = r#"```shell
+echo "out"; echo "err" >&2; exit 3
+```
+"#;
+
+    #[test]
+    fn test_exec_code_stderr_merge() {
+        let id = "test_exec_code_stderr_merge";
+        let mut params = HashMap::new();
+        params.insert("stderr".to_string(), "merge".to_string());
+        let result = exec(FAILING, &params, id, &context(RunMode::Write)).unwrap();
+        assert!(result.contains(r#"exit_code="3" -->"#));
+        assert!(result.ends_with("<!-- result -->\n```\nout\nerr\n```\n"));
+    }
+
+    #[test]
+    fn test_exec_code_stderr_separate() {
+        let id = "test_exec_code_stderr_separate";
+        let mut params = HashMap::new();
+        params.insert("stderr".to_string(), "separate".to_string());
+        let result = exec(FAILING, &params, id, &context(RunMode::Write)).unwrap();
+        assert!(
+            result.ends_with("<!-- result -->\n```\nout\n```\n<!-- stderr -->\n```\nerr\n```\n")
+        );
+    }
+
+    #[test]
+    fn test_exec_code_missing_exec_path() {
+        let id = "test_exec_code_missing_exec_path";
+        let mut params = HashMap::new();
+        params.insert("exec_path".to_string(), "/no/such/dir".to_string());
+        let error = exec(EXEC_RESULT, &params, id, &context(RunMode::Write)).unwrap_err();
+        assert_eq!(error.to_string(), "exec_path '/no/such/dir' does not exist");
+
+        let mut params = HashMap::new();
+        params.insert("r_exec_path".to_string(), "Cargo.toml".to_string());
+        let error = exec(EXEC_RESULT, &params, id, &context(RunMode::Write)).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("Cargo.toml' is not a directory"));
+    }
+
+    #[test]
+    fn test_exec_code_fail_on_error() {
+        let id = "test_exec_code_fail_on_error";
+        let mut params = HashMap::new();
+        params.insert("stderr".to_string(), "separate".to_string());
+        params.insert("fail_on_error".to_string(), "true".to_string());
+        let error = exec(FAILING, &params, id, &context(RunMode::Write)).unwrap_err();
+        assert_eq!(error.to_string(), "exited with status 3: err");
+
+        // Such as a compiler's diagnostics when a build fails
+        let only_stdout = "```shell\necho \"main.c:1: error\"; exit 1\n```\n";
+        let error = exec(only_stdout, &params, id, &context(RunMode::Write)).unwrap_err();
+        assert_eq!(error.to_string(), "exited with status 1: main.c:1: error");
+    }
+
+    #[test]
+    fn test_exec_code_cache_always_runs_once() {
+        let id = "test_exec_code_cache_always_runs_once";
+        let mut params = HashMap::new();
+        params.insert("cache".to_string(), "always".to_string());
+        let first = exec(
+            "```shell\necho \"test\"; echo \"another\"\n```\n",
+            &params,
+            id,
+            &context(RunMode::Write),
+        )
+        .unwrap();
+        assert!(first.ends_with("<!-- result -->\n```\ntest\nanother\n```\n"));
+        let edited = first.replace("another\n```\n", "kept\n```\n");
+        assert_eq!(
+            exec(&edited, &params, id, &context(RunMode::Write)).unwrap(),
+            edited
+        );
+    }
+
+    #[test]
+    fn test_exec_code_cached_keeps_exit_code() {
+        let id = "test_exec_code_cached_keeps_exit_code";
+        let mut params = HashMap::new();
+        params.insert("cache".to_string(), "always".to_string());
+        let text = r#"<!--embed-meta hash="B5HCpFdy+3cU7xRfkyL2vzhMbKGkz7UN7HEih00CeQM": last_run="1111": exit_code="3" -->
+```shell
+echo "out"; echo "err" >&2; exit 3
+```
+<!-- result -->
+```
+out
+```
+"#;
+        assert_eq!(
+            exec(text, &params, id, &context(RunMode::Write)).unwrap(),
+            text
+        );
+    }
+
+    #[test]
+    fn test_exec_code_force_ignores_cache() {
+        let id = "test_exec_code_force_ignores_cache";
+        let mut params = HashMap::new();
+        params.insert("cache".to_string(), "always".to_string());
+        let text = EXEC_RESULT.replace("test\nanother\n```\n", "cached\n```\n");
+        let context = Context {
+            force: true,
+            ..context(RunMode::Write)
+        };
+        let result = exec(&text, &params, id, &context).unwrap();
+        assert!(result.ends_with("```\ntest\nanother\n```\n"));
+    }
+
+    #[test]
+    fn test_exec_code_timeout() {
+        let id = "test_exec_code_timeout";
+        let mut params = HashMap::new();
+        params.insert("timeout".to_string(), "1".to_string());
+        let result = exec(
+            r#"```shell
+echo "partial"; sleep 30
+```
+"#,
+            &params,
+            id,
+            &context(RunMode::Write),
+        )
+        .unwrap();
+        assert!(result.contains(r#"exit_code="timeout" -->"#));
+        assert!(result.ends_with("```\npartial\n```\n<!-- timed out after 1s -->\n"));
+    }
+
+    #[test]
+    fn test_exec_code_invalid_timeout() {
+        let id = "test_exec_code_invalid_timeout";
+        let mut params = HashMap::new();
+        params.insert("timeout".to_string(), "forever".to_string());
+        let error = exec(FAILING, &params, id, &context(RunMode::Write)).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::InvalidParam(ref p, _) if p == "timeout"));
+    }
+
+    #[test]
+    fn test_exec_params() {
+        let params = |pairs: &[(&str, &str)]| {
+            ExecParams::from_params(
+                &pairs
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+        };
+        let defaults = params(&[]).unwrap();
+        assert_eq!(defaults.lang, "shell");
+        assert_eq!(defaults.stderr, Stderr::Ignore);
+        assert!(!defaults.fail_on_error);
+        assert_eq!(defaults.cache, None);
+
+        let parsed = params(&[
+            ("cache", "never"),
+            ("ttl", "7d"),
+            ("fail_on_error", "true"),
+            ("depends_on", "*.json, data/*"),
+            ("stderr", "separate"),
+        ])
+        .unwrap();
+        assert_eq!(parsed.cache, Some(Cache::Never));
+        assert_eq!(parsed.ttl, Some(Duration::from_secs(7 * 24 * 60 * 60)));
+        assert!(parsed.fail_on_error);
+        assert_eq!(
+            parsed.depends_on,
+            Some(vec!["*.json".to_string(), "data/*".to_string()])
+        );
+        assert_eq!(parsed.stderr, Stderr::Separate);
+
+        let error = params(&[("stderr", "both")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid param 'stderr': 'both' should be one of ignore, merge or separate"
+        );
+        let error = params(&[("o-lang", "json")]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unknown param 'o-lang', did you mean 'o_lang'?"
+        );
+    }
+
+    #[test]
+    fn test_exec_code_env() {
+        let id = "test_exec_code_env";
+        let context = Context {
+            env: BTreeMap::from([
+                ("GREETING".to_string(), "hello".to_string()),
+                ("NAME".to_string(), "world".to_string()),
+            ]),
+            ..context(RunMode::Write)
+        };
+        let result = exec(
+            r#"```shell
+echo "$GREETING $NAME"
+```
+"#,
+            &HashMap::new(),
+            id,
+            &context,
+        )
+        .unwrap();
+        assert!(result.ends_with("```\nhello world\n```\n"));
+    }
+
+    #[test]
+    fn test_exec_code_upstream_output_changes_hash() {
+        let id = "test_exec_code_upstream_output_changes_hash";
+        let params = HashMap::new();
+        let text = r#"```shell
+cat $$upstream_output_changes_hash$$
+```
+"#;
+        let hash = || {
+            let result = exec(text, &params, id, &context(RunMode::Write)).unwrap();
+            Regex::new(r#"hash="(.*?)""#)
+                .unwrap()
+                .captures(&result)
+                .unwrap()[1]
+                .to_string()
+        };
+        let upstream = context(RunMode::Write)
+            .outputs
+            .path("upstream_output_changes_hash");
+        fs::create_dir_all("../test_out_dir").unwrap();
+        fs::write(&upstream, "first\n").unwrap();
+        let first = hash();
+        assert_eq!(hash(), first);
+        fs::write(&upstream, "second\n").unwrap();
+        assert_ne!(hash(), first);
+    }
+
+    #[test]
+    fn test_exec_code_depends_on() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().to_str().unwrap();
+        fs::write(format!("{}/a.json", dir), "{}").unwrap();
+        fs::write(format!("{}/notes.txt", dir), "").unwrap();
+        let id = "test_exec_code_depends_on";
+        let mut params = HashMap::new();
+        params.insert("exec_path".to_string(), dir.to_string());
+        params.insert("depends_on".to_string(), "*.json, missing/*".to_string());
+        let text = r#"```shell
+cat *.json
+```
+"#;
+        let first = exec(text, &params, id, &context(RunMode::Write)).unwrap();
+        assert!(first.contains(r#"depends_on="a.json=RBNvo1WzZ4oR" -->"#));
+
+        fs::write(format!("{}/a.json", dir), "[]").unwrap();
+        fs::write(format!("{}/b.json", dir), "[]").unwrap();
+        let second = exec(&first, &params, id, &context(RunMode::Write)).unwrap();
+        assert!(second.contains(r#"depends_on="a.json=T1PNoYwrqgwD,b.json=T1PNoYwrqgwD" -->"#));
+        assert_eq!(
+            stale_reasons(&first, &second),
+            vec!["a.json changed", "b.json was added"]
+        );
+        assert_eq!(
+            stale_reasons(&second, &first),
+            vec!["a.json changed", "b.json was removed"]
+        );
+    }
+
+    #[test]
+    fn test_dependencies_with_separators_in_paths() {
+        let dependencies = vec![
+            ("a,b.json".to_string(), "RBNvo1WzZ4oR".to_string()),
+            ("k=v\\x.json".to_string(), "T1PNoYwrqgwD".to_string()),
+        ];
+        let list = format_dependencies(&dependencies);
+        assert_eq!(list, r"a\,b.json=RBNvo1WzZ4oR,k=v\\x.json=T1PNoYwrqgwD");
+        assert_eq!(
+            parse_dependencies(&list),
+            dependencies.into_iter().collect::<BTreeMap<_, _>>()
+        );
+        // Lists written before paths were escaped read the same
+        assert_eq!(parse_dependencies("a.json=RBNvo1WzZ4oR,b.json=x").len(), 2);
+    }
+
+    #[test]
+    fn test_stale_reasons_without_dependencies() {
+        let before = r#"<!--embed-meta hash="old": last_run="1111" -->
+```shell
+date
+```
+"#;
+        let after = before.replace("old", "new");
+        assert_eq!(
+            stale_reasons("```shell\ndate\n```\n", &after),
+            vec!["never run"]
+        );
+        assert_eq!(
+            stale_reasons(before, &after),
+            vec!["code, env or an embed it reads changed"]
+        );
+        assert_eq!(
+            stale_reasons(before, &format!("{}<!-- result -->\n", before)),
+            vec!["output changed"]
+        );
+    }
+
+    #[test]
+    fn test_exec_code_env_changes_hash() {
+        let text = r#"```shell
+echo "$NAME"
+```
+"#;
+        let hash = |env: &[(&str, &str)]| {
+            let id = "test_exec_code_env_changes_hash";
+            let context = Context {
+                env: env
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                ..context(RunMode::Write)
+            };
+            let result = exec(text, &HashMap::new(), id, &context).unwrap();
+            Regex::new(r#"hash="(.*?)""#)
+                .unwrap()
+                .captures(&result)
+                .unwrap()[1]
+                .to_string()
+        };
+        let without = hash(&[]);
+        assert_ne!(hash(&[("NAME", "a")]), without);
+        assert_ne!(hash(&[("NAME", "a")]), hash(&[("NAME", "b")]));
+    }
+
+    #[test]
+    fn test_exec_code_no_code_block() {
+        let params = HashMap::new();
+        let id = "test_exec_code_no_code_block";
+        let result = exec("just some text\n", &params, id, &context(RunMode::Write));
+        assert!(matches!(
+            *result.unwrap_err().kind,
+            ErrorKind::MissingCodeBlock
+        ));
+    }
 }
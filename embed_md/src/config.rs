@@ -0,0 +1,260 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use embed_md_traits::{EmbedError, ErrorKind};
+
+use crate::files::FileFilter;
+use crate::languages::Interpreter;
+use crate::Options;
+
+/// The project config, the nearest one in a markdown file's directory or above applies to it
+pub const CONFIG_FILE: &str = "embed_md.toml";
+
+/// Where `exec-code` outputs are written when nothing sets `out_dir`
+pub const DEFAULT_OUT_DIR: &str = "~/.embed_md";
+
+/// Settings shared by every markdown file in a project
+///
+/// ```toml
+/// out_dir = ".embed_md"
+/// cache = "hash"
+/// timeout = "1m"
+/// exclude = ["drafts/"]
+///
+/// [languages.sqlite]
+/// command = "sqlite3"
+/// args = ["data.sqlite"]
+///
+/// [plugins]
+/// toc = "scripts/toc.py"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The file this was loaded from, none when no project config was found
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Relative to the config file
+    pub out_dir: Option<String>,
+    pub cache: Option<String>,
+    pub timeout: Option<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// Added to the built in languages and [`crate::LANGUAGES_FILE`], replacing any with the same name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub languages: BTreeMap<String, Interpreter>,
+    /// Programs run for functions that aren't built in, by function name. Relative to the config
+    /// file, see [`crate::PluginRequest`]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub plugins: BTreeMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, EmbedError> {
+        let content = fs::read_to_string(path).map_err(|e| EmbedError::from(e).with_file(path))?;
+        let mut config: Config = toml::from_str(&content).map_err(|e| {
+            EmbedError::new(ErrorKind::InvalidParam(
+                CONFIG_FILE.to_string(),
+                e.message().to_string(),
+            ))
+            .with_file(path)
+        })?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let relative_to_config = |value: &mut String| {
+            if !value.starts_with('~') && Path::new(value.as_str()).is_relative() {
+                *value = dir.join(value.as_str()).to_string_lossy().to_string();
+            }
+        };
+        if let Some(out_dir) = config.out_dir.as_mut() {
+            relative_to_config(out_dir);
+        }
+        config.plugins.values_mut().for_each(relative_to_config);
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// Loads the nearest config file in `dir` or any directory above it, like `.editorconfig`. A
+    /// directory that doesn't exist or has no config above it gets the default config.
+    pub fn discover(dir: &Path) -> Result<Config, EmbedError> {
+        let dir = match fs::canonicalize(dir) {
+            Ok(dir) => dir,
+            Err(_) => return Ok(Config::default()),
+        };
+        match dir
+            .ancestors()
+            .map(|ancestor| ancestor.join(CONFIG_FILE))
+            .find(|candidate| candidate.is_file())
+        {
+            Some(path) => Config::load(&path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    /// The config for a markdown file or a directory of them
+    pub fn for_path(path: &Path) -> Result<Config, EmbedError> {
+        match path.is_dir() {
+            true => Config::discover(path),
+            false => Config::discover(
+                path.parent()
+                    .filter(|p| !p.as_os_str().is_empty())
+                    .unwrap_or(Path::new(".")),
+            ),
+        }
+    }
+
+    /// The params every embed under this config starts from
+    pub fn params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        for (key, value) in [
+            ("out_dir", &self.out_dir),
+            ("cache", &self.cache),
+            ("timeout", &self.timeout),
+        ] {
+            if let Some(value) = value {
+                params.insert(key.to_string(), value.clone());
+            }
+        }
+        params
+    }
+
+    /// The filter to walk directories with, globs given on the command line replace the config's
+    pub fn filter(&self, cli: &FileFilter) -> FileFilter {
+        let layered = |cli: &Vec<String>, config: &Vec<String>| match cli.is_empty() {
+            true => config.clone(),
+            false => cli.clone(),
+        };
+        FileFilter {
+            include: layered(&cli.include, &self.include),
+            exclude: layered(&cli.exclude, &self.exclude),
+        }
+    }
+
+    /// The settings a run uses, with the command line layered over this config and built in
+    /// defaults filling in anything neither sets
+    pub fn resolve(&self, options: &Options) -> Config {
+        let filter = self.filter(&options.filter);
+        Config {
+            path: self.path.clone(),
+            out_dir: options
+                .defaults
+                .get("out_dir")
+                .or(self.out_dir.as_ref())
+                .cloned()
+                .or_else(|| Some(DEFAULT_OUT_DIR.to_string())),
+            cache: options
+                .defaults
+                .get("cache")
+                .or(self.cache.as_ref())
+                .cloned(),
+            timeout: options
+                .defaults
+                .get("timeout")
+                .or(self.timeout.as_ref())
+                .cloned(),
+            include: filter.include,
+            exclude: filter.exclude,
+            languages: self.languages.clone(),
+            plugins: self.plugins.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project() -> tempfile::TempDir {
+        let project = tempfile::tempdir().unwrap();
+        let root = project.path();
+        fs::create_dir_all(root.join("docs/nested")).unwrap();
+        fs::write(
+            root.join(CONFIG_FILE),
+            r#"
+out_dir = "outputs"
+cache = "hash"
+exclude = ["drafts/"]
+
+[languages.lua]
+command = "lua"
+
+[plugins]
+toc = "scripts/toc.py"
+"#,
+        )
+        .unwrap();
+        project
+    }
+
+    #[test]
+    fn test_discover_walks_upward() {
+        let project = project();
+        let root = fs::canonicalize(project.path()).unwrap();
+        let config = Config::for_path(&root.join("docs/nested/doc.md")).unwrap();
+        assert_eq!(config.path, Some(root.join(CONFIG_FILE)));
+        assert_eq!(config.cache.as_deref(), Some("hash"));
+        assert_eq!(
+            config.out_dir.as_deref().map(Path::new),
+            Some(root.join("outputs").as_path())
+        );
+        assert_eq!(
+            config.languages.get("lua"),
+            Some(&Interpreter::new("lua", &[]))
+        );
+        assert_eq!(
+            Path::new(&config.plugins["toc"]),
+            root.join("scripts/toc.py")
+        );
+    }
+
+    #[test]
+    fn test_discover_nearest_wins() {
+        let project = project();
+        let root = project.path();
+        fs::write(root.join("docs").join(CONFIG_FILE), "cache = \"always\"\n").unwrap();
+        let config = Config::discover(&root.join("docs/nested")).unwrap();
+        assert_eq!(config.cache.as_deref(), Some("always"));
+        assert!(config.exclude.is_empty());
+    }
+
+    #[test]
+    fn test_discover_missing_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            Config::discover(&dir.path().join("does/not/exist")).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn test_load_unknown_key() {
+        let project = project();
+        fs::write(project.path().join(CONFIG_FILE), "cahce = \"hash\"\n").unwrap();
+        let error = Config::discover(project.path()).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::InvalidParam(ref p, _) if p == CONFIG_FILE));
+    }
+
+    #[test]
+    fn test_resolve_layers_command_line() {
+        let config = Config {
+            cache: Some("hash".to_string()),
+            timeout: Some("1m".to_string()),
+            include: vec!["docs/**".to_string()],
+            exclude: vec!["drafts/".to_string()],
+            ..Config::default()
+        };
+        let mut options = Options::default();
+        options
+            .defaults
+            .insert("timeout".to_string(), "5s".to_string());
+        options.filter.exclude = vec!["*.mdx".to_string()];
+        let resolved = config.resolve(&options);
+        assert_eq!(resolved.out_dir.as_deref(), Some(DEFAULT_OUT_DIR));
+        assert_eq!(resolved.cache.as_deref(), Some("hash"));
+        assert_eq!(resolved.timeout.as_deref(), Some("5s"));
+        assert_eq!(resolved.include, vec!["docs/**"]);
+        assert_eq!(resolved.exclude, vec!["*.mdx"]);
+    }
+}
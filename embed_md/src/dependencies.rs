@@ -0,0 +1,76 @@
+/// Orders nodes so each comes after everything it depends on, `dependencies[i]` being the nodes
+/// node `i` depends on. Nodes that don't depend on each other keep their original order. A cycle
+/// is returned as the chain of nodes that forms it, starting and ending with the same node.
+pub fn execution_order(dependencies: &[Vec<usize>]) -> Result<Vec<usize>, Vec<usize>> {
+    #[derive(Clone, Copy, PartialEq)]
+    enum State {
+        Unvisited,
+        Visiting,
+        Done,
+    }
+
+    fn visit(
+        node: usize,
+        dependencies: &[Vec<usize>],
+        states: &mut [State],
+        path: &mut Vec<usize>,
+        order: &mut Vec<usize>,
+    ) -> Result<(), Vec<usize>> {
+        match states[node] {
+            State::Done => return Ok(()),
+            State::Visiting => {
+                let start = path.iter().position(|n| *n == node).unwrap();
+                let mut chain = path[start..].to_vec();
+                chain.push(node);
+                return Err(chain);
+            }
+            State::Unvisited => {}
+        }
+        states[node] = State::Visiting;
+        path.push(node);
+        for dependency in &dependencies[node] {
+            visit(*dependency, dependencies, states, path, order)?;
+        }
+        path.pop();
+        states[node] = State::Done;
+        order.push(node);
+        Ok(())
+    }
+
+    let mut states = vec![State::Unvisited; dependencies.len()];
+    let mut order = Vec::with_capacity(dependencies.len());
+    for node in 0..dependencies.len() {
+        visit(node, dependencies, &mut states, &mut Vec::new(), &mut order)?;
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_execution_order_keeps_independent_order() {
+        assert_eq!(
+            execution_order(&[vec![], vec![], vec![]]),
+            Ok(vec![0, 1, 2])
+        );
+    }
+
+    #[test]
+    fn test_execution_order_dependencies_first() {
+        assert_eq!(
+            execution_order(&[vec![2], vec![], vec![3], vec![]]),
+            Ok(vec![3, 2, 0, 1])
+        );
+    }
+
+    #[test]
+    fn test_execution_order_cycle() {
+        assert_eq!(
+            execution_order(&[vec![], vec![2], vec![3], vec![1]]),
+            Err(vec![1, 2, 3, 1])
+        );
+        assert_eq!(execution_order(&[vec![0]]), Err(vec![0, 0]));
+    }
+}
@@ -0,0 +1,222 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+
+use embed_md_traits::{EmbedError, ErrorKind};
+
+/// Added to a file's name for the copy kept by [`write_atomic`]
+pub const BACKUP_EXTENSION: &str = "bak";
+
+/// Extensions treated as markdown when walking a directory
+pub const MARKDOWN_EXTENSIONS: [&str; 3] = ["md", "markdown", "mdx"];
+
+/// Restricts which files are processed when walking a directory. Globs use `.gitignore` syntax
+/// and are matched relative to the directory being walked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileFilter {
+    /// When non-empty, only files matching one of these are processed
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+/// Resolves a path to the markdown files to process. A file is always returned as is, a directory
+/// is walked recursively skipping anything ignored by `.gitignore` or the filter.
+pub fn collect_files(path_str: &str, filter: &FileFilter) -> Result<Vec<PathBuf>, EmbedError> {
+    let path = Path::new(path_str);
+    match path.is_file() || path.is_dir() {
+        false => Err(EmbedError::new(ErrorKind::InvalidPath).with_file(path)),
+        true => match path.is_file() {
+            true => Ok(vec![PathBuf::from(path_str)]),
+            false => walk(path, filter),
+        },
+    }
+}
+
+fn walk(root: &Path, filter: &FileFilter) -> Result<Vec<PathBuf>, EmbedError> {
+    let mut overrides = OverrideBuilder::new(root);
+    let globs = filter
+        .include
+        .iter()
+        .map(|glob| (glob.clone(), glob.clone()))
+        .chain(
+            filter
+                .exclude
+                .iter()
+                .map(|glob| (glob.clone(), format!("!{}", glob))),
+        );
+    for (glob, pattern) in globs {
+        overrides.add(&pattern).map_err(|e| {
+            EmbedError::new(ErrorKind::InvalidPattern(glob, e.to_string())).with_file(root)
+        })?;
+    }
+    let overrides = overrides.build().map_err(|e| {
+        EmbedError::new(ErrorKind::InvalidPattern(String::new(), e.to_string())).with_file(root)
+    })?;
+
+    let mut files = Vec::new();
+    for entry in WalkBuilder::new(root)
+        .overrides(overrides)
+        // Respect .gitignore even when the docs aren't inside a git repository
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build()
+    {
+        let entry =
+            entry.map_err(|e| EmbedError::from(std::io::Error::other(e)).with_file(root))?;
+        let path = entry.path();
+        if entry.file_type().is_some_and(|t| t.is_file())
+            && path
+                .extension()
+                .is_some_and(|x| MARKDOWN_EXTENSIONS.iter().any(|ext| x == *ext))
+        {
+            files.push(path.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+/// Replaces a file's content by writing a temporary file next to it and renaming it over the
+/// original, so the file is never left half written. The original's permissions are kept, and with
+/// `backup` its previous content is copied to `<file>.bak` first.
+pub fn write_atomic(file: &Path, content: &str, backup: bool) -> Result<(), EmbedError> {
+    let in_file = |e: std::io::Error| EmbedError::from(e).with_file(file);
+    // Written where a symlink points so the link itself is kept
+    let target = fs::canonicalize(file).map_err(in_file)?;
+    let dir = target.parent().unwrap_or(Path::new("/"));
+    let permissions = fs::metadata(&target).map_err(in_file)?.permissions();
+    if backup {
+        let mut name = file.as_os_str().to_owned();
+        name.push(format!(".{}", BACKUP_EXTENSION));
+        fs::copy(file, name).map_err(in_file)?;
+    }
+    let mut temp = tempfile::NamedTempFile::new_in(dir).map_err(in_file)?;
+    temp.write_all(content.as_bytes()).map_err(in_file)?;
+    temp.as_file().sync_all().map_err(in_file)?;
+    fs::set_permissions(temp.path(), permissions).map_err(in_file)?;
+    temp.persist(&target).map_err(|e| in_file(e.error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn docs_tree() -> tempfile::TempDir {
+        let tree = tempfile::tempdir().unwrap();
+        let root = tree.path();
+        for dir in ["docs/guide", "docs/drafts", "skipped"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "README.md",
+            "notes.txt",
+            "docs/index.markdown",
+            "docs/guide/intro.mdx",
+            "docs/guide/setup.md",
+            "docs/drafts/wip.md",
+            "skipped/ignored.md",
+        ] {
+            fs::write(root.join(file), "").unwrap();
+        }
+        fs::write(root.join(".gitignore"), "skipped/\n").unwrap();
+        tree
+    }
+
+    fn relative(root: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| f.strip_prefix(root).unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_collect_files_recursive() {
+        let tree = docs_tree();
+        let root = tree.path();
+        let files = collect_files(root.to_str().unwrap(), &FileFilter::default()).unwrap();
+        assert_eq!(
+            relative(root, files),
+            vec![
+                "README.md",
+                "docs/drafts/wip.md",
+                "docs/guide/intro.mdx",
+                "docs/guide/setup.md",
+                "docs/index.markdown",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_files_include_exclude() {
+        let tree = docs_tree();
+        let root = tree.path();
+        let filter = FileFilter {
+            include: vec!["docs/**".to_string()],
+            exclude: vec!["drafts/".to_string(), "*.mdx".to_string()],
+        };
+        let files = collect_files(root.to_str().unwrap(), &filter).unwrap();
+        assert_eq!(
+            relative(root, files),
+            vec!["docs/guide/setup.md", "docs/index.markdown"]
+        );
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let file = root.join("doc.md");
+        fs::write(&file, "before").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        write_atomic(&file, "after", true).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "after");
+        assert_eq!(
+            fs::read_to_string(root.join("doc.md.bak")).unwrap(),
+            "before"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&file).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o640);
+        }
+
+        write_atomic(&file, "again", false).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("doc.md.bak")).unwrap(),
+            "before"
+        );
+        // Only the file and its backup, no temporary files left behind
+        assert_eq!(fs::read_dir(root).unwrap().count(), 2);
+
+        #[cfg(unix)]
+        {
+            let link = root.join("link.md");
+            std::os::unix::fs::symlink("doc.md", &link).unwrap();
+            write_atomic(&link, "through the link", false).unwrap();
+            assert!(fs::symlink_metadata(&link)
+                .unwrap()
+                .file_type()
+                .is_symlink());
+            assert_eq!(fs::read_to_string(&file).unwrap(), "through the link");
+        }
+    }
+
+    #[test]
+    fn test_collect_files_single_file_ignores_filter() {
+        let filter = FileFilter {
+            include: vec![],
+            exclude: vec!["*.md".to_string()],
+        };
+        let files = collect_files("../README.md", &filter).unwrap();
+        assert_eq!(files, vec![PathBuf::from("../README.md")]);
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::ops::Range;
+use std::sync::Arc;
+
+use embed_md_traits::{EmbedError, EmbedParams, ErrorKind, FunctionTag, ParamHelp};
+
+use crate::chunks::{ExecCode, ExecParams, Identity};
+
+/// What a tag gives the function it names
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionArgs {
+    pub id: String,
+    /// The tag's params layered over the defaults, everything else about the run is given to
+    /// [`FunctionTag::transform`] in a [`embed_md_traits::Context`]
+    pub params: HashMap<String, String>,
+    /// Whether this is the closing tag
+    pub is_end: bool,
+    /// Where the tag is in the document
+    pub range: Range<usize>,
+}
+
+/// Builds the function for a tag, failing if the tag's params can't be used
+pub type FunctionFactory =
+    dyn Fn(FunctionArgs) -> Result<Box<dyn FunctionTag>, EmbedError> + Send + Sync;
+
+/// Maps the function named in a tag, such as `exec-code`, to the factory that builds it. Functions
+/// can be added from Rust code and used by setting [`crate::Options::functions`].
+///
+/// ```
+/// use embed_md::{
+///     Context, EmbedError, FunctionArgs, FunctionRegistry, FunctionTag, Options, Rangeable,
+/// };
+/// use std::ops::Range;
+///
+/// #[derive(Debug)]
+/// struct Upper {
+///     id: String,
+///     range: Range<usize>,
+/// }
+///
+/// impl Rangeable for Upper {
+///     fn range(&self) -> Range<usize> {
+///         self.range.clone()
+///     }
+///
+///     fn id(&self) -> String {
+///         self.id.clone()
+///     }
+/// }
+///
+/// impl FunctionTag for Upper {
+///     fn transform(&self, text: String, _context: &Context) -> Result<String, EmbedError> {
+///         Ok(text.to_uppercase())
+///     }
+/// }
+///
+/// let mut functions = FunctionRegistry::default();
+/// functions.register("upper", |args: FunctionArgs| {
+///     Ok(Box::new(Upper {
+///         id: args.id,
+///         range: args.range,
+///     }))
+/// });
+/// let options = Options {
+///     functions,
+///     ..Options::default()
+/// };
+/// let content = "<!--embed upper id=\"a\" -->\nshout\n<!--embed upper id=\"a\" /-->\n";
+/// let rendered = embed_md::render(content, None, &options).unwrap();
+/// assert_eq!(rendered, "<!--embed upper id=\"a\" -->\nSHOUT\n<!--embed upper id=\"a\" /-->\n");
+/// ```
+#[derive(Clone)]
+pub struct FunctionRegistry {
+    functions: HashMap<String, Arc<FunctionFactory>>,
+    /// The params of the functions registered with them
+    params: HashMap<String, Vec<ParamHelp>>,
+}
+
+impl Default for FunctionRegistry {
+    /// The built in `identity` and `exec-code` functions
+    fn default() -> Self {
+        let mut registry = FunctionRegistry {
+            functions: HashMap::new(),
+            params: HashMap::new(),
+        };
+        registry.register("identity", |args: FunctionArgs| {
+            Ok(Box::new(Identity::new(args.id, args.is_end, args.range)))
+        });
+        registry.register_with_params::<ExecParams, _>("exec-code", |args: FunctionArgs| {
+            Ok(Box::new(ExecCode::new(args)?))
+        });
+        registry
+    }
+}
+
+impl FunctionRegistry {
+    /// Adds a function, replacing any with the same name including the built in ones
+    pub fn register<F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn(FunctionArgs) -> Result<Box<dyn FunctionTag>, EmbedError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        self.params.remove(&name);
+        self.functions.insert(name, Arc::new(factory));
+    }
+
+    /// Adds a function whose tags are read into `P`, keeping its params for help
+    pub fn register_with_params<P, F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        P: EmbedParams,
+        F: Fn(FunctionArgs) -> Result<Box<dyn FunctionTag>, EmbedError> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        self.functions.insert(name.clone(), Arc::new(factory));
+        self.params.insert(name, P::help());
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+    }
+
+    /// The names of every function, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.functions.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// The params of a function, none if it wasn't registered with them
+    pub fn params(&self, name: &str) -> Option<&[ParamHelp]> {
+        self.params.get(name).map(Vec::as_slice)
+    }
+
+    /// Builds the function a tag names
+    pub fn create(
+        &self,
+        function: &str,
+        args: FunctionArgs,
+    ) -> Result<Box<dyn FunctionTag>, EmbedError> {
+        match self.functions.get(function) {
+            Some(factory) => factory(args),
+            None => Err(
+                EmbedError::new(ErrorKind::UnknownFunction(function.to_string())).with_id(args.id),
+            ),
+        }
+    }
+}
+
+impl Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FunctionRegistry")
+            .field("functions", &self.names())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::tests::context;
+    use embed_md_traits::RunMode;
+
+    fn args(id: &str) -> FunctionArgs {
+        FunctionArgs {
+            id: id.to_string(),
+            params: HashMap::new(),
+            is_end: false,
+            range: 0..0,
+        }
+    }
+
+    #[test]
+    fn test_builtin_functions() {
+        let registry = FunctionRegistry::default();
+        assert_eq!(registry.names(), vec!["exec-code", "identity"]);
+        let identity = registry.create("identity", args("a")).unwrap();
+        assert_eq!(identity.id(), "a");
+        let context = context(RunMode::Write);
+        assert_eq!(
+            identity.transform("text".to_string(), &context).unwrap(),
+            "text"
+        );
+    }
+
+    #[test]
+    fn test_unknown_function() {
+        let error = FunctionRegistry::default()
+            .create("nope", args("a"))
+            .unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::UnknownFunction(ref f) if f == "nope"));
+        assert_eq!(error.id.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_exec_code_params() {
+        let registry = FunctionRegistry::default();
+        let params = registry.params("exec-code").unwrap();
+        let cache = params.iter().find(|p| p.name == "cache").unwrap();
+        assert_eq!(cache.kind, "always|hash|time|never");
+        assert_eq!(
+            params
+                .iter()
+                .find(|p| p.name == "lang")
+                .unwrap()
+                .to_string(),
+            "lang=<text>  The language to run the code block with (default: shell)"
+        );
+        assert!(registry.params("identity").is_none());
+
+        let mut tag = args("a");
+        tag.params.insert("cahce".to_string(), "hash".to_string());
+        let error = registry.create("exec-code", tag).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[a] unknown param 'cahce', did you mean 'cache'?"
+        );
+    }
+
+    #[test]
+    fn test_register_replaces() {
+        let mut registry = FunctionRegistry::default();
+        registry.register("exec-code", |args: FunctionArgs| {
+            Err(EmbedError::exec(format!("{} is disabled", args.id)))
+        });
+        let error = registry.create("exec-code", args("a")).unwrap_err();
+        assert_eq!(error.to_string(), "a is disabled");
+        assert!(registry.params("exec-code").is_none());
+    }
+}
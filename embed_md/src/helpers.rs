@@ -1,12 +1,87 @@
-use std::collections::HashMap;
-
-pub fn extract_map(t: &str) -> HashMap<String, String> {
-    t.split(':')
-        .map(|t| t.trim())
-        .filter(|t| !t.is_empty())
-        .map(|t| {
-            let parts: Vec<String> = t.split('=').map(|f| f.replace('\"', "")).collect();
-            (parts[0].to_string(), parts[1].to_string())
+/// Parses `KEY=value` pairs separated by whitespace or new lines as found in `.env` files. Values
+/// may be quoted to include spaces, lines starting with `#` are comments and `export` is ignored.
+pub fn parse_env(t: &str) -> Result<Vec<(String, String)>, String> {
+    let mut env = Vec::new();
+    for line in t.lines().map(str::trim) {
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut rest = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        while !rest.is_empty() {
+            let (key, after) = rest
+                .split_once('=')
+                .ok_or_else(|| format!("'{}' should look like KEY=value", rest))?;
+            if key.is_empty() || key.contains(char::is_whitespace) {
+                return Err(format!("'{}' is not a valid variable name", key));
+            }
+            let (value, after) = match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let end = after[1..]
+                        .find(quote)
+                        .ok_or_else(|| format!("unclosed quote in value of {}", key))?;
+                    (&after[1..end + 1], &after[end + 2..])
+                }
+                _ => after.split_at(after.find(char::is_whitespace).unwrap_or(after.len())),
+            };
+            env.push((key.to_string(), value.to_string()));
+            rest = after.trim_start();
+        }
+    }
+    Ok(env)
+}
+
+/// Parses the `env` param of a tag, `KEY=value` pairs separated by commas
+pub fn parse_env_list(t: &str) -> Result<Vec<(String, String)>, String> {
+    t.split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+            _ => Err(format!("'{}' should look like KEY=value", pair)),
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_env() {
+        let env = parse_env(
+            r#"
+# comment
+export API=https://example.com
+NAME="two words" EMPTY= QUOTED='single'
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            env,
+            pairs(&[
+                ("API", "https://example.com"),
+                ("NAME", "two words"),
+                ("EMPTY", ""),
+                ("QUOTED", "single")
+            ])
+        );
+        assert!(parse_env("NOVALUE").is_err());
+        assert!(parse_env("A=\"unclosed").is_err());
+    }
+
+    #[test]
+    fn test_parse_env_list() {
+        assert_eq!(
+            parse_env_list("KEY=value, OTHER=x=y").unwrap(),
+            pairs(&[("KEY", "value"), ("OTHER", "x=y")])
+        );
+        assert!(parse_env_list("KEY").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+use embed_md_traits::EmbedError;
+
+/// Limits how many embeds run at once, shared by every file in a run
+#[derive(Debug)]
+pub struct Jobs {
+    limit: usize,
+    available: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Jobs {
+    /// A limit of 0 is treated as 1
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Jobs {
+            limit,
+            available: Mutex::new(limit),
+            freed: Condvar::new(),
+        }
+    }
+
+    pub fn is_parallel(&self) -> bool {
+        self.limit > 1
+    }
+
+    /// Runs `task` once one of the jobs is free, the job is freed again even if `task` panics
+    pub fn run<T>(&self, task: impl FnOnce() -> T) -> T {
+        let mut available = self
+            .freed
+            .wait_while(self.available.lock().unwrap(), |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+        drop(available);
+        let _job = Job(self);
+        task()
+    }
+
+    /// Runs `task` for each item using as many threads as there are jobs, returning the results in
+    /// the order of the items
+    pub fn map<I: Sync, T: Send>(&self, items: &[I], task: impl Fn(&I) -> T + Sync) -> Vec<T> {
+        let next = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<T>>> = Mutex::new(items.iter().map(|_| None).collect());
+        thread::scope(|scope| {
+            for _ in 0..self.limit.min(items.len()) {
+                scope.spawn(|| loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    if index >= items.len() {
+                        break;
+                    }
+                    let result = task(&items[index]);
+                    results.lock().unwrap()[index] = Some(result);
+                });
+            }
+        });
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+/// One of the jobs, taken until dropped
+struct Job<'a>(&'a Jobs);
+
+impl Drop for Job<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// A task in [`run_graph`] that panicked, with the panic's message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Panicked(pub String);
+
+impl Panicked {
+    fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "no message".to_string(),
+            },
+        };
+        Panicked(message)
+    }
+}
+
+impl From<Panicked> for EmbedError {
+    fn from(panicked: Panicked) -> Self {
+        EmbedError::exec(format!("panicked: {}", panicked.0))
+    }
+}
+
+/// How far a node in [`run_graph`] got
+enum State<T, E> {
+    Pending,
+    Running,
+    Done(Result<T, E>),
+    /// Not run because something it depends on failed
+    Skipped,
+}
+
+/// Runs `task` for every node once all the nodes it depends on have succeeded, `dependencies[i]`
+/// being the nodes node `i` depends on, which must not form a cycle. Nodes run as soon as they're
+/// ready on as many threads as there are jobs, and results are returned in node order. A task
+/// that panics fails its node with [`Panicked`]. Nodes downstream of a failure aren't run and have
+/// no result.
+pub fn run_graph<T: Send, E: Send + From<Panicked>>(
+    dependencies: &[Vec<usize>],
+    jobs: &Jobs,
+    task: impl Fn(usize) -> Result<T, E> + Sync,
+) -> Vec<Option<Result<T, E>>> {
+    let states: Mutex<Vec<State<T, E>>> =
+        Mutex::new(dependencies.iter().map(|_| State::Pending).collect());
+    let changed = Condvar::new();
+    thread::scope(|scope| {
+        for _ in 0..jobs.limit.min(dependencies.len()) {
+            scope.spawn(|| loop {
+                let mut guard = states.lock().unwrap();
+                let node = loop {
+                    if let Some(node) = next_ready(&mut guard, dependencies) {
+                        break node;
+                    }
+                    if !guard.iter().any(|state| matches!(state, State::Pending)) {
+                        // Skipping nodes may have settled the last of them
+                        changed.notify_all();
+                        return;
+                    }
+                    guard = changed.wait(guard).unwrap();
+                };
+                guard[node] = State::Running;
+                drop(guard);
+                let result = catch_unwind(AssertUnwindSafe(|| jobs.run(|| task(node))))
+                    .unwrap_or_else(|payload| Err(Panicked::new(payload).into()));
+                states.lock().unwrap()[node] = State::Done(result);
+                changed.notify_all();
+            });
+        }
+    });
+    states
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|state| match state {
+            State::Done(result) => Some(result),
+            State::Pending | State::Running | State::Skipped => None,
+        })
+        .collect()
+}
+
+/// The first pending node whose dependencies have all succeeded, skipping the pending nodes that
+/// depend on a failure along the way
+fn next_ready<T, E>(states: &mut [State<T, E>], dependencies: &[Vec<usize>]) -> Option<usize> {
+    loop {
+        let mut skipped = false;
+        for (node, node_dependencies) in dependencies.iter().enumerate() {
+            if !matches!(states[node], State::Pending) {
+                continue;
+            }
+            let failed = node_dependencies
+                .iter()
+                .any(|d| matches!(states[*d], State::Done(Err(_)) | State::Skipped));
+            if failed {
+                states[node] = State::Skipped;
+                skipped = true;
+            } else if node_dependencies
+                .iter()
+                .all(|d| matches!(states[*d], State::Done(Ok(_))))
+            {
+                return Some(node);
+            }
+        }
+        // A skip can fail nodes that were already looked at
+        if !skipped {
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_jobs_limit() {
+        let jobs = Jobs::new(2);
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+        jobs.map(&[0; 6], |_| {
+            let (running, most) = (&running, &most);
+            run_graph(&[vec![], vec![]], &jobs, |_| -> Result<(), Panicked> {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })
+        });
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_map_keeps_order() {
+        let jobs = Jobs::new(4);
+        let results = jobs.map(&[30, 0, 20, 10], |delay| {
+            thread::sleep(Duration::from_millis(*delay));
+            *delay
+        });
+        assert_eq!(results, vec![30, 0, 20, 10]);
+    }
+
+    #[test]
+    fn test_run_graph_waits_for_dependencies() {
+        let finished: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        let start = Instant::now();
+        let results = run_graph(
+            &[vec![2], vec![], vec![], vec![0, 1]],
+            &Jobs::new(4),
+            |node| {
+                if node == 2 {
+                    thread::sleep(Duration::from_millis(50));
+                }
+                finished.lock().unwrap().push(node);
+                Ok::<usize, Panicked>(node)
+            },
+        );
+        assert_eq!(
+            results,
+            vec![Some(Ok(0)), Some(Ok(1)), Some(Ok(2)), Some(Ok(3))]
+        );
+        let finished = finished.into_inner().unwrap();
+        let position = |node| finished.iter().position(|n| *n == node).unwrap();
+        assert!(position(2) < position(0));
+        assert!(position(0) < position(3) && position(1) < position(3));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_run_graph_skips_after_failure() {
+        let results = run_graph(
+            &[vec![], vec![0], vec![]],
+            &Jobs::new(2),
+            |node| match node {
+                0 => Err(Panicked("failed".to_string())),
+                _ => Ok(node),
+            },
+        );
+        assert_eq!(
+            results,
+            vec![Some(Err(Panicked("failed".to_string()))), None, Some(Ok(2))]
+        );
+    }
+
+    #[test]
+    fn test_run_graph_survives_panics() {
+        let jobs = Jobs::new(2);
+        let results = run_graph(
+            &[vec![], vec![0], vec![], vec![]],
+            &jobs,
+            |node| match node {
+                0 => panic!("mid-transform"),
+                _ => Ok::<usize, Panicked>(node),
+            },
+        );
+        assert_eq!(
+            results,
+            vec![
+                Some(Err(Panicked("mid-transform".to_string()))),
+                None,
+                Some(Ok(2)),
+                Some(Ok(3))
+            ]
+        );
+        // The panicking task's job was freed
+        assert_eq!(*jobs.available.lock().unwrap(), 2);
+    }
+}
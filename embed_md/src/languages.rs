@@ -0,0 +1,608 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use embed_md_traits::EmbedError;
+
+/// Where users can add or override languages, alongside the default output directory
+pub const LANGUAGES_FILE: &str = "~/.embed_md/languages.toml";
+
+/// A command that runs code written to its stdin. With an `extension` the code is instead written
+/// to a temporary `main.<extension>` file, `{src}` and `{bin}` in the args are replaced with the
+/// file and a path to build a binary to, and `run` is executed once the command succeeds.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Interpreter {
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extension: Option<String>,
+    /// Command and args to run after building, empty uses the build's own output
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub run: Vec<String>,
+}
+
+impl Interpreter {
+    pub fn new(command: &str, args: &[&str]) -> Self {
+        Interpreter {
+            command: command.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            extension: None,
+            run: Vec::new(),
+        }
+    }
+
+    /// A compiled language whose build writes `{bin}` which is then run
+    pub fn compiled(command: &str, args: &[&str], extension: &str) -> Self {
+        Interpreter {
+            extension: Some(extension.to_string()),
+            run: vec!["{bin}".to_string()],
+            ..Interpreter::new(command, args)
+        }
+    }
+
+    /// Parses a command line such as `sqlite3 db.sqlite`, arguments are split on whitespace
+    pub fn parse(command_line: &str) -> Option<Self> {
+        let mut parts = command_line.split_whitespace();
+        let command = parts.next()?;
+        Some(Interpreter {
+            command: command.to_string(),
+            args: parts.map(str::to_string).collect(),
+            extension: None,
+            run: Vec::new(),
+        })
+    }
+
+    /// Runs the code. When building a compiled language fails the compiler's output is returned
+    /// as stdout so it ends up in the result. Building and running share the timeout.
+    pub fn run(&self, code: &str, settings: &RunSettings) -> Result<Execution, EmbedError> {
+        let deadline = settings.deadline();
+        let extension = match &self.extension {
+            Some(extension) => extension,
+            None => {
+                let mut command = settings.command(&self.command, &self.args);
+                command.stdin(Stdio::piped());
+                return execute(command, code, deadline);
+            }
+        };
+        let build_dir = tempfile::tempdir()?;
+        let src = build_dir.path().join(format!("main.{}", extension));
+        let bin = build_dir.path().join("main");
+        fs::write(&src, code)?;
+        let fill = |args: &[String]| -> Vec<String> {
+            args.iter()
+                .map(|arg| {
+                    arg.replace("{src}", &src.to_string_lossy())
+                        .replace("{bin}", &bin.to_string_lossy())
+                })
+                .collect()
+        };
+
+        let mut build = settings.command(&self.command, &fill(&self.args));
+        build.stderr(Stdio::piped());
+        let build = execute(build, "", deadline)?;
+        if build.timed_out || !build.output.status.success() {
+            let output = build.output;
+            return Ok(Execution {
+                output: Output {
+                    status: output.status,
+                    stdout: [output.stdout, output.stderr].concat(),
+                    stderr: Vec::new(),
+                },
+                timed_out: build.timed_out,
+            });
+        }
+        match self.run.split_first() {
+            None => {
+                if !settings.capture_stderr {
+                    std::io::stderr().write_all(&build.output.stderr)?;
+                }
+                Ok(build)
+            }
+            Some((command, args)) => execute(
+                settings.command(&fill(std::slice::from_ref(command))[0], &fill(args)),
+                "",
+                deadline,
+            ),
+        }
+    }
+}
+
+/// How code is run, independent of the language
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSettings {
+    /// Directory the code runs from
+    pub dir: String,
+    /// Only captured when asked for, otherwise it goes to the terminal
+    pub capture_stderr: bool,
+    pub timeout: Option<Duration>,
+    /// Set on top of the environment embed_md runs in
+    pub env: BTreeMap<String, String>,
+}
+
+impl RunSettings {
+    /// When the code has to be done by if it has a timeout, counting from now. A timeout too
+    /// long to fall on a clock is the same as none.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout))
+    }
+
+    pub(crate) fn command(&self, program: &str, args: &[String]) -> Command {
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .current_dir(&self.dir)
+            .envs(&self.env)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(match self.capture_stderr {
+                true => Stdio::piped(),
+                false => Stdio::inherit(),
+            });
+        command
+    }
+}
+
+/// What running code produced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Execution {
+    pub output: Output,
+    /// The timeout expired and the code was killed, the output is whatever it wrote until then
+    pub timed_out: bool,
+}
+
+/// How often a command with a timeout is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long killed code gets to let go of its pipes before what it wrote so far is taken as its
+/// output, a process that left its group may hold them open indefinitely
+const KILL_GRACE: Duration = Duration::from_millis(500);
+
+/// Runs a command writing `input` to its stdin if piped. With a deadline the command runs in its
+/// own process group, and once the deadline passes the whole group is killed so anything it
+/// started goes with it. That includes processes it left running in the background holding its
+/// output open after it exited. Without a deadline it stays in embed_md's group, so Ctrl-C
+/// reaches it.
+pub(crate) fn execute(
+    mut command: Command,
+    input: &str,
+    deadline: Option<Instant>,
+) -> Result<Execution, EmbedError> {
+    #[cfg(unix)]
+    if deadline.is_some() {
+        std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    }
+    let program = command.get_program().to_string_lossy().to_string();
+    let mut child = command
+        .spawn()
+        .map_err(|e| EmbedError::exec(format!("failed to start {}: {}", program, e)))?;
+
+    // Pipes are serviced on their own threads so a full pipe can't stall the timeout
+    let stdin = child.stdin.take().map(|mut stdin| {
+        let input = input.as_bytes().to_vec();
+        on_thread(move || match stdin.write_all(&input) {
+            // The code doesn't have to read all of its input
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        })
+    });
+    let stdout = child.stdout.take().map(Reader::spawn);
+    let stderr = child.stderr.take().map(Reader::spawn);
+
+    let (status, mut timed_out) = match deadline {
+        None => (child.wait()?, false),
+        Some(deadline) => loop {
+            if let Some(status) = child.try_wait()? {
+                break (status, false);
+            }
+            if Instant::now() >= deadline {
+                kill_group(&mut child)?;
+                break (child.wait()?, true);
+            }
+            thread::sleep(POLL_INTERVAL);
+        },
+    };
+
+    let mut read = |reader: Option<Reader>| -> std::io::Result<Vec<u8>> {
+        let reader = match reader {
+            Some(reader) => reader,
+            None => return Ok(Vec::new()),
+        };
+        let closed = match reader.wait(deadline) {
+            Some(closed) => Some(closed),
+            None => {
+                kill_group(&mut child)?;
+                timed_out = true;
+                reader.wait(Some(Instant::now() + KILL_GRACE))
+            }
+        };
+        closed.transpose()?;
+        Ok(reader.take())
+    };
+    let (stdout, stderr) = (read(stdout)?, read(stderr)?);
+    // Code that exited without reading all of its input may have left it to something still
+    // running, which was killed by now if it had a deadline
+    if let Some(written) = stdin.and_then(|stdin| wait_until(&stdin, deadline)) {
+        written?;
+    }
+    Ok(Execution {
+        output: Output {
+            status,
+            stdout,
+            stderr,
+        },
+        timed_out,
+    })
+}
+
+/// Runs `task` on its own thread, its result arrives on the receiver
+fn on_thread<T: Send + 'static>(task: impl FnOnce() -> T + Send + 'static) -> Receiver<T> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(task());
+    });
+    receiver
+}
+
+/// What arrives on `receiver` by the deadline, waiting as long as it takes without one
+fn wait_until<T>(receiver: &Receiver<T>, deadline: Option<Instant>) -> Option<T> {
+    match deadline {
+        None => receiver.recv().ok(),
+        Some(deadline) => receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .ok(),
+    }
+}
+
+/// Reads a pipe on its own thread, keeping what was read so far so the output of code that
+/// never closes it isn't lost
+struct Reader {
+    read: Arc<Mutex<Vec<u8>>>,
+    closed: Receiver<std::io::Result<()>>,
+}
+
+impl Reader {
+    fn spawn(mut pipe: impl Read + Send + 'static) -> Self {
+        let read = Arc::new(Mutex::new(Vec::new()));
+        let buffer = read.clone();
+        let closed = on_thread(move || {
+            let mut chunk = [0; 8192];
+            loop {
+                match pipe.read(&mut chunk) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => buffer.lock().unwrap().extend_from_slice(&chunk[..n]),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        });
+        Reader { read, closed }
+    }
+
+    /// Waits for the pipe to close, none if it is still open at the deadline
+    fn wait(&self, deadline: Option<Instant>) -> Option<std::io::Result<()>> {
+        wait_until(&self.closed, deadline)
+    }
+
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.read.lock().unwrap())
+    }
+}
+
+#[cfg(unix)]
+fn kill_group(child: &mut Child) -> std::io::Result<()> {
+    // The child leads its own group, signalling the negated id reaches every process in it
+    match unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } {
+        0 => Ok(()),
+        _ => match std::io::Error::last_os_error() {
+            // Already gone
+            e if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+            e => Err(e),
+        },
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(child: &mut Child) -> std::io::Result<()> {
+    child.kill()
+}
+
+/// Maps the `lang` of an `exec-code` tag to the interpreter that runs it. A name ending in `*`
+/// matches any `lang` starting with the rest of it, exact names take precedence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageRegistry {
+    languages: HashMap<String, Interpreter>,
+}
+
+impl Default for LanguageRegistry {
+    fn default() -> Self {
+        let builtins = [
+            ("shell", Interpreter::new("sh", &[])),
+            ("sh", Interpreter::new("sh", &[])),
+            ("zsh", Interpreter::new("zsh", &[])),
+            ("bash", Interpreter::new("bash", &[])),
+            ("python*", Interpreter::new("python3", &[])),
+            ("node", Interpreter::new("node", &["-"])),
+            ("javascript", Interpreter::new("node", &["-"])),
+            ("js", Interpreter::new("node", &["-"])),
+            ("deno", Interpreter::new("deno", &["run", "-"])),
+            ("typescript", Interpreter::new("deno", &["run", "-"])),
+            ("ts", Interpreter::new("deno", &["run", "-"])),
+            ("ruby", Interpreter::new("ruby", &[])),
+            ("perl", Interpreter::new("perl", &[])),
+            ("sqlite3", Interpreter::new("sqlite3", &[])),
+            ("sqlite", Interpreter::new("sqlite3", &[])),
+            ("jq", Interpreter::new("jq", &["-n", "-f", "/dev/stdin"])),
+            (
+                "rust",
+                Interpreter::compiled(
+                    "rustc",
+                    &["--edition", "2021", "-o", "{bin}", "{src}"],
+                    "rs",
+                ),
+            ),
+            (
+                "c",
+                Interpreter::compiled("cc", &["-o", "{bin}", "{src}"], "c"),
+            ),
+            (
+                "go",
+                Interpreter::compiled("go", &["build", "-o", "{bin}", "{src}"], "go"),
+            ),
+        ];
+        LanguageRegistry {
+            languages: builtins
+                .into_iter()
+                .map(|(lang, interpreter)| (lang.to_string(), interpreter))
+                .collect(),
+        }
+    }
+}
+
+impl LanguageRegistry {
+    /// The built in languages extended by [`LANGUAGES_FILE`] if it exists. The file is read once
+    /// it loads, later calls in the same process get the same languages.
+    pub fn load() -> Result<Self, EmbedError> {
+        static LOADED: OnceLock<LanguageRegistry> = OnceLock::new();
+        if let Some(registry) = LOADED.get() {
+            return Ok(registry.clone());
+        }
+        let mut registry = LanguageRegistry::default();
+        let path = shellexpand::tilde(LANGUAGES_FILE).to_string();
+        if Path::new(&path).is_file() {
+            registry.extend_from_file(Path::new(&path))?;
+        }
+        Ok(LOADED.get_or_init(|| registry).clone())
+    }
+
+    /// Adds every language in a toml file, replacing any with the same name
+    ///
+    /// ```toml
+    /// [sqlite]
+    /// command = "sqlite3"
+    /// args = ["data.sqlite"]
+    /// ```
+    pub fn extend_from_file(&mut self, path: &Path) -> Result<(), EmbedError> {
+        let content = fs::read_to_string(path).map_err(|e| EmbedError::from(e).with_file(path))?;
+        let languages: HashMap<String, Interpreter> = toml::from_str(&content)
+            .map_err(|e| EmbedError::exec(e.to_string()).with_file(path))?;
+        self.extend(languages);
+        Ok(())
+    }
+
+    pub fn extend(&mut self, languages: impl IntoIterator<Item = (String, Interpreter)>) {
+        self.languages.extend(languages);
+    }
+
+    pub fn get(&self, lang: &str) -> Option<&Interpreter> {
+        self.languages.get(lang).or_else(|| {
+            self.languages
+                .iter()
+                .filter_map(|(name, interpreter)| {
+                    let prefix = name.strip_suffix('*')?;
+                    lang.starts_with(prefix)
+                        .then_some((prefix.len(), interpreter))
+                })
+                .max_by_key(|(len, _)| *len)
+                .map(|(_, interpreter)| interpreter)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_languages() {
+        let registry = LanguageRegistry::default();
+        assert_eq!(registry.get("shell"), Some(&Interpreter::new("sh", &[])));
+        assert_eq!(
+            registry.get("node"),
+            Some(&Interpreter::new("node", &["-"]))
+        );
+        assert_eq!(registry.get("cobol"), None);
+    }
+
+    #[test]
+    fn test_prefix_languages() {
+        let mut registry = LanguageRegistry::default();
+        assert_eq!(
+            registry.get("python3.12"),
+            Some(&Interpreter::new("python3", &[]))
+        );
+        let mut languages = HashMap::new();
+        languages.insert("python2*".to_string(), Interpreter::new("python2", &[]));
+        registry.extend(languages);
+        assert_eq!(
+            registry.get("python2.7"),
+            Some(&Interpreter::new("python2", &[]))
+        );
+        assert_eq!(
+            registry.get("python3"),
+            Some(&Interpreter::new("python3", &[]))
+        );
+    }
+
+    #[test]
+    fn test_extend_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("languages.toml");
+        fs::write(
+            path,
+            r#"
+[sqlite]
+command = "sqlite3"
+args = ["data.sqlite"]
+
+[lua]
+command = "lua"
+"#,
+        )
+        .unwrap();
+        let mut registry = LanguageRegistry::default();
+        registry.extend_from_file(path).unwrap();
+        assert_eq!(
+            registry.get("sqlite"),
+            Some(&Interpreter::new("sqlite3", &["data.sqlite"]))
+        );
+        assert_eq!(registry.get("lua"), Some(&Interpreter::new("lua", &[])));
+    }
+
+    fn settings(timeout: Option<Duration>) -> RunSettings {
+        RunSettings {
+            dir: ".".to_string(),
+            capture_stderr: false,
+            timeout,
+            env: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_run_timeout_kills_group() {
+        let shell = Interpreter::new("sh", &[]);
+        let start = Instant::now();
+        // The background sleep holds stdout open, it has to be killed for the run to finish
+        let execution = shell
+            .run(
+                "echo started; sleep 30 & sleep 30\n",
+                &settings(Some(Duration::from_millis(200))),
+            )
+            .unwrap();
+        assert!(execution.timed_out);
+        assert_eq!(
+            String::from_utf8_lossy(&execution.output.stdout),
+            "started\n"
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_run_timeout_background_holds_output() {
+        let shell = Interpreter::new("sh", &[]);
+        let start = Instant::now();
+        // The shell exits at once, leaving the sleep holding stdout open
+        let execution = shell
+            .run(
+                "echo started; sleep 30 &\n",
+                &settings(Some(Duration::from_millis(300))),
+            )
+            .unwrap();
+        assert!(execution.timed_out);
+        assert_eq!(
+            String::from_utf8_lossy(&execution.output.stdout),
+            "started\n"
+        );
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_without_timeout_keeps_group() {
+        let shell = Interpreter::new("sh", &[]);
+        let group = |settings: &RunSettings| {
+            let output = shell.run("ps -o pgid= -p $$\n", settings).unwrap().output;
+            String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .parse::<i32>()
+                .unwrap()
+        };
+        let own = unsafe { libc::getpgrp() };
+        assert_eq!(group(&settings(None)), own);
+        assert_ne!(group(&settings(Some(Duration::from_secs(30)))), own);
+    }
+
+    #[test]
+    fn test_run_compiled_shares_timeout() {
+        // Building and running each fit in the timeout, but not both
+        let slow = Interpreter {
+            command: "sh".to_string(),
+            args: vec![
+                "-c".to_string(),
+                "sleep 0.4; cp {src} {bin}; chmod +x {bin}".to_string(),
+            ],
+            extension: Some("sh".to_string()),
+            run: vec!["{bin}".to_string()],
+        };
+        let execution = slow
+            .run(
+                "#!/bin/sh\nsleep 0.4; echo ran\n",
+                &settings(Some(Duration::from_millis(600))),
+            )
+            .unwrap();
+        assert!(execution.timed_out);
+        assert_eq!(String::from_utf8_lossy(&execution.output.stdout), "");
+    }
+
+    #[test]
+    fn test_run_within_timeout() {
+        let shell = Interpreter::new("sh", &[]);
+        let execution = shell
+            .run("echo done\n", &settings(Some(Duration::from_secs(30))))
+            .unwrap();
+        assert!(!execution.timed_out);
+        assert_eq!(String::from_utf8_lossy(&execution.output.stdout), "done\n");
+    }
+
+    #[test]
+    fn test_run_compiled() {
+        let rust = LanguageRegistry::default().get("rust").unwrap().clone();
+        let output = rust
+            .run(
+                "fn main() {\n    println!(\"{}\", 6 * 7);\n}\n",
+                &settings(None),
+            )
+            .unwrap()
+            .output;
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n");
+    }
+
+    #[test]
+    fn test_run_compiled_build_error() {
+        let rust = LanguageRegistry::default().get("rust").unwrap().clone();
+        let output = rust
+            .run("fn main() { not_a_function(); }\n", &settings(None))
+            .unwrap()
+            .output;
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("not_a_function"));
+    }
+
+    #[test]
+    fn test_parse_interpreter() {
+        assert_eq!(
+            Interpreter::parse("sqlite3  db.sqlite"),
+            Some(Interpreter::new("sqlite3", &["db.sqlite"]))
+        );
+        assert_eq!(Interpreter::parse("  "), None);
+    }
+}
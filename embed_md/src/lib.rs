@@ -1,19 +1,99 @@
+// The derives name this crate, which inside it is itself
+extern crate self as embed_md;
+
 mod chunks;
+mod config;
+mod dependencies;
+mod files;
+mod functions;
 mod helpers;
+mod jobs;
+mod languages;
+mod outputs;
+mod parser;
+mod plugins;
+mod watch;
 
-use crate::chunks::{FunctionType, Identity};
-use crate::helpers::extract_map;
-use embed_md_traits::FunctionTag;
-use embed_md_traits::Rangeable;
-use std::collections::HashMap;
+use crate::chunks::Identity;
+use crate::dependencies::execution_order;
+use crate::files::{collect_files, write_atomic};
+use crate::helpers::{parse_env, parse_env_list};
+use crate::jobs::{run_graph, Jobs};
+pub use chunks::{Cache, ExecParams, Stderr};
+pub use config::{Config, CONFIG_FILE, DEFAULT_OUT_DIR};
+pub use embed_md_derive::{EmbedParams, ParamValue};
+/// What the `EmbedParams` and `ParamValue` derives expand to
+#[doc(hidden)]
+pub use embed_md_traits as __private;
+pub use embed_md_traits::{
+    Context, EmbedError, EmbedParams, ErrorKind, FunctionTag, Location, OutputStore, ParamHelp,
+    ParamValue, Rangeable, RunMode,
+};
+pub use files::{FileFilter, BACKUP_EXTENSION, MARKDOWN_EXTENSIONS};
+pub use functions::{FunctionArgs, FunctionFactory, FunctionRegistry};
+pub use languages::{Interpreter, LanguageRegistry, LANGUAGES_FILE};
+pub use outputs::OutputDir;
+pub use parser::{
+    parse_blocks, parse_directives, parse_tags, quote_value, tokenize, Attribute, Block,
+    Diagnostic, Directive, Span, Tag, Token, TokenKind,
+};
+pub use plugins::{
+    find_plugin, Plugin, PluginRequest, PluginResponse, PLUGIN_PREFIX, PLUGIN_PROTOCOL_VERSION,
+};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+pub use watch::{watch, WatchEvent};
+
+use similar::TextDiff;
+
+/// The path used to read markdown from stdin and write the result to stdout
+pub const STDIN: &str = "-";
+
+/// The change a run would make to a single embed
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmbedDiff {
+    pub file: PathBuf,
+    pub id: String,
+    /// Unified diff against the file on disk
+    pub diff: String,
+}
+
+/// Settings that apply to every file in a run
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Only run the embeds with these ids, every other embed is left as is. Empty runs them all
+    pub ids: Vec<String>,
+    pub filter: FileFilter,
+    /// Params from the command line, applied to every embed and winning over its tag
+    pub defaults: HashMap<String, String>,
+    /// Environment variables for every embed, these win over any set in the markdown
+    pub env: Vec<(String, String)>,
+    /// How many embeds may run at once across every file, 0 is treated as 1
+    pub jobs: usize,
+    /// Keep a copy of each file as it was before it is rewritten
+    pub backup: bool,
+    /// Run every embed even when its cache says the result is current
+    pub force: bool,
+    /// The functions tags can name, the built in ones unless replaced
+    pub functions: FunctionRegistry,
+}
+
+/// An embed whose regenerated content doesn't match what is on disk
+#[derive(Debug, Clone, PartialEq)]
+pub struct StaleEmbed {
+    pub file: PathBuf,
+    pub id: String,
+    /// What changed since the embed was last run, such as a file it depends on
+    pub reasons: Vec<String>,
+}
 
 #[derive(Debug, Clone)]
 struct TagFunctionPair {
-    start: FunctionType,
-    end: FunctionType,
+    start: Arc<dyn FunctionTag>,
+    end: Arc<dyn FunctionTag>,
 }
 
 impl TagFunctionPair {
@@ -28,21 +108,26 @@ struct Chunk {
     text: String,
     opening_tag: String,
     closing_tag: String,
+    context: Context,
 }
 
 impl Chunk {
-    fn transform(&self, id: Option<String>) -> Chunk {
-        let text = match id {
-            Some(id) if id == self.pair.start.id() => self.pair.start.transform(self.text.clone()),
-            Some(_) => self.text.clone(),
-            _ => self.pair.start.transform(self.text.clone()),
+    /// Runs the chunk's function, unless `ids` is given and doesn't include the chunk
+    fn transform(&self, ids: &[String]) -> Result<Chunk, EmbedError> {
+        let text = match ids.is_empty() || ids.contains(&self.pair.start.id()) {
+            true => self
+                .pair
+                .start
+                .transform(self.text.clone(), &self.context)?,
+            false => self.text.clone(),
         };
-        Chunk {
+        Ok(Chunk {
             text,
             pair: self.pair.clone(),
             opening_tag: self.opening_tag.clone(),
             closing_tag: self.closing_tag.clone(),
-        }
+            context: self.context.clone(),
+        })
     }
 
     fn print_representation(&self) -> String {
@@ -50,14 +135,14 @@ impl Chunk {
     }
 }
 
-fn new_identity_chunk(range: Range<usize>, message: &str) -> Chunk {
+fn new_identity_chunk(range: Range<usize>, message: &str, context: &Context) -> Chunk {
     let tag_pair = TagFunctionPair {
-        start: FunctionType::Identity(Identity::new(
+        start: Arc::new(Identity::new(
             "identity".to_string(),
             false,
             range.start..range.start,
         )),
-        end: FunctionType::Identity(Identity::new(
+        end: Arc::new(Identity::new(
             "identity".to_string(),
             true,
             range.end..range.end,
@@ -69,130 +154,488 @@ fn new_identity_chunk(range: Range<usize>, message: &str) -> Chunk {
         text: message[internal_range].to_string(),
         opening_tag: "".to_string(),
         closing_tag: "".to_string(),
+        context: context.clone(),
     }
 }
 
-fn process_to_chunks(message: &str, file: &Path) -> Vec<Chunk> {
-    let re =
-        regex::Regex::new(r#"<!--embed (.*?) id="(.*?)" +((\w*=".*?":? )*)? ?(/?)-->\n"#).unwrap();
-    let mut to_collection: Vec<Vec<FunctionType>> = Vec::new();
-    for cap in re.captures_iter(message) {
-        let id = cap[2].to_string();
-        let function = cap[1].to_string();
-        let mut params: HashMap<String, String> = extract_map(&cap[3]);
-        params.insert(
-            "exec_location".to_string(),
-            file.parent().unwrap().to_str().unwrap().to_string(),
-        );
-        params.insert(
-            "file_name".to_string(),
-            file.file_name().unwrap().to_str().unwrap().to_string(),
-        );
-        params.insert("exec_id".to_string(), id.clone());
-        let is_end = !cap[5].is_empty();
-        let range = cap.get(0).unwrap().range();
+/// Reads the `.env` style file given with `--env-file`
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>, EmbedError> {
+    let content = std::fs::read_to_string(path).map_err(|e| EmbedError::from(e).with_file(path))?;
+    parse_env(&content)
+        .map_err(|e| EmbedError::new(ErrorKind::InvalidParam("env".to_string(), e)).with_file(path))
+}
+
+/// Collects the variables of every `<!--embed-config env ... -->` block, later blocks win
+fn document_env(message: &str) -> Result<Vec<(String, String)>, EmbedError> {
+    let (blocks, diagnostics) = parse_blocks(message, "config env");
+    if let Some(diagnostic) = diagnostics.into_iter().next() {
+        return Err(diagnostic.into());
+    }
+    let mut env = Vec::new();
+    for block in blocks {
+        env.extend(parse_env(&block.body).map_err(|e| {
+            EmbedError::new(ErrorKind::InvalidParam("env".to_string(), e))
+                .with_location(block.span.location())
+        })?);
+    }
+    Ok(env)
+}
+
+/// Collects the params of every `<!--embed-defaults ... -->` directive, later directives win
+fn document_defaults(message: &str) -> Result<HashMap<String, String>, EmbedError> {
+    let (directives, diagnostics) = parse_directives(message, "defaults");
+    if let Some(diagnostic) = diagnostics.into_iter().next() {
+        return Err(diagnostic.into());
+    }
+    Ok(directives
+        .into_iter()
+        .flat_map(|directive| directive.attributes)
+        .map(|attribute| (attribute.key, attribute.value))
+        .collect())
+}
 
-        let tag_function = FunctionType::from(id, function, params, is_end, range);
+fn process_to_chunks(
+    message: &str,
+    file: Option<&Path>,
+    mode: RunMode,
+    options: &Options,
+    config: &Config,
+) -> Result<Vec<Chunk>, EmbedError> {
+    let (tags, diagnostics) = parse_tags(message);
+    if let Some(diagnostic) = diagnostics.into_iter().next() {
+        return Err(diagnostic.into());
+    }
+    let document_env = document_env(message)?;
+    // The command line wins over tags, which win over the document's defaults, then the project's
+    let mut defaults = config.params();
+    defaults.extend(document_defaults(message)?);
+    defaults.extend(options.defaults.clone());
+    let source = file.map(Path::to_path_buf);
+    // Content without a file runs from the current directory
+    let root = file
+        .and_then(Path::parent)
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+    let outputs = |params: &HashMap<String, String>| {
+        let dir = params
+            .get("out_dir")
+            .map_or(DEFAULT_OUT_DIR, String::as_str);
+        Arc::new(OutputDir::new(dir, file, &root))
+    };
+    let document = Context {
+        outputs: outputs(&defaults),
+        source,
+        root: root.clone(),
+        config: Arc::new(config.resolve(options)),
+        mode,
+        force: options.force,
+        env: BTreeMap::new(),
+    };
+    let mut to_collection: Vec<Vec<(Arc<dyn FunctionTag>, Context)>> = Vec::new();
+    for tag in tags {
+        let Tag {
+            function,
+            id,
+            attributes,
+            is_end,
+            span,
+        } = tag;
+        let range = span.range();
+        let location = span.location();
+        let tag_params: HashMap<String, String> = attributes
+            .into_iter()
+            .map(|attribute| (attribute.key, attribute.value))
+            .collect();
+        let mut params = defaults.clone();
+        params.extend(tag_params.clone());
+        params.extend(options.defaults.clone());
+        // The document sets the base environment, the tag adds to it and the command line wins
+        let mut env: BTreeMap<String, String> = document_env.iter().cloned().collect();
+        if let Some(tag_env) = params.get("env") {
+            env.extend(parse_env_list(tag_env).map_err(|e| {
+                EmbedError::new(ErrorKind::InvalidParam("env".to_string(), e))
+                    .with_id(&id)
+                    .with_location(location)
+            })?);
+        }
+        env.extend(options.env.iter().cloned());
+        let context = Context {
+            outputs: outputs(&params),
+            env,
+            ..document.clone()
+        };
+        // Defaults are set for every embed, a function that declares its params only gets those
+        // it knows while its own tag is still checked in full
+        if let Some(known) = options.functions.params(&function) {
+            params.retain(|key, _| {
+                tag_params.contains_key(key) || known.iter().any(|param| param.name == key)
+            });
+        }
+        let args = FunctionArgs {
+            id,
+            params,
+            is_end,
+            range,
+        };
+        // A function that isn't registered may be a plugin
+        let plugin = match options.functions.contains(&function) {
+            true => None,
+            false => find_plugin(&function, config),
+        };
+        let created = match plugin {
+            Some(program) => {
+                Ok(Box::new(Plugin::new(program, function, args)) as Box<dyn FunctionTag>)
+            }
+            None => options.functions.create(&function, args),
+        };
+        let tag_function: Arc<dyn FunctionTag> =
+            created.map_err(|e| e.with_location(location))?.into();
         if let Some(last) = to_collection.last_mut() {
-            if last.first().unwrap().id() == tag_function.id() {
-                last.push(tag_function);
+            if last.first().unwrap().0.id() == tag_function.id() {
+                last.push((tag_function, context));
                 continue;
             }
         }
-        to_collection.push(vec![tag_function]);
+        to_collection.push(vec![(tag_function, context)]);
     }
-    let pairs: Vec<TagFunctionPair> = to_collection
+    // The opening tag's context is the embed's
+    let pairs: Vec<(TagFunctionPair, Context)> = to_collection
         .into_iter()
         .map(|mut v| {
-            if v.first().unwrap() == v.last().unwrap() {
-                panic!("No end tag found for {}", v.first().unwrap().id());
+            let first = &v.first().unwrap().0;
+            let error = |kind| {
+                EmbedError::new(kind)
+                    .with_id(first.id())
+                    .with_location(Location::from_byte(message, first.range().start))
+            };
+            if v.len() == 1 {
+                return Err(error(ErrorKind::MissingEndTag));
             }
             if v.len() > 2 {
-                panic!(
-                    "ID {} has more than 2 tags - print {:?}",
-                    v.first().unwrap().id(),
-                    v
-                );
-            }
-            TagFunctionPair {
-                start: v.remove(0),
-                end: v.remove(0),
+                return Err(error(ErrorKind::DuplicateTag(v.len())));
             }
+            let (start, context) = v.remove(0);
+            let (end, _) = v.remove(0);
+            Ok((TagFunctionPair { start, end }, context))
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
     let mut location = 0;
     let mut processed: Vec<Chunk> = pairs
         .iter()
-        .map(|pair| {
+        .map(|(pair, context)| {
             let pair_clone = pair.clone();
-            let identity_chunk = new_identity_chunk(location..pair.start.range().start, message);
+            let identity_chunk =
+                new_identity_chunk(location..pair.start.range().start, message, &document);
             let tag_chunk = Chunk {
                 pair: pair.clone(),
                 text: message[pair.internal_range()].to_string(),
                 opening_tag: message[pair.start.range().clone()].to_string(),
                 closing_tag: message[pair.end.range().clone()].to_string(),
+                context: context.clone(),
             };
             location = pair_clone.end.range().end;
             (identity_chunk, tag_chunk)
         })
         .flat_map(|(identity_chunk, tag_chunk)| vec![identity_chunk, tag_chunk])
         .collect();
-    processed.push(new_identity_chunk(location..message.len(), message));
-    processed
+    processed.push(new_identity_chunk(
+        location..message.len(),
+        message,
+        &document,
+    ));
+    Ok(processed)
+}
+
+/// Adds the file to an error when the content came from one
+fn in_file(error: EmbedError, file: Option<&Path>) -> EmbedError {
+    match file {
+        Some(file) => error.with_file(file),
+        None => error,
+    }
 }
 
-fn process_file(content: &str, id: Option<String>, file: PathBuf) {
-    let chunks = process_to_chunks(content, &file);
-    match chunks.len() {
-        // If no chunks, do nothing
-        0 => {}
-        _ => {
-            let file_content = chunks
+/// The order to run chunks in so an embed runs after every embed whose output it reads through
+/// `$$id$$`. References to ids that aren't in the content are left to whatever is on disk.
+fn chunk_order(
+    chunks: &[Chunk],
+    content: &str,
+) -> Result<(Vec<usize>, Vec<Vec<usize>>), EmbedError> {
+    // Identity chunks have no tags and can't be referenced
+    let ids: HashMap<String, usize> = chunks
+        .iter()
+        .enumerate()
+        .filter(|(_, chunk)| !chunk.opening_tag.is_empty())
+        .map(|(index, chunk)| (chunk.pair.start.id(), index))
+        .collect();
+    let dependencies: Vec<Vec<usize>> = chunks
+        .iter()
+        .map(|chunk| {
+            chunk
+                .pair
+                .start
+                .dependencies(&chunk.text)
                 .iter()
-                .map(|chunk| chunk.transform(id.clone()).print_representation())
-                .collect::<Vec<String>>()
-                .join("");
-            std::fs::write(file, file_content).expect("Error writing to _file");
+                .filter_map(|id| ids.get(id).copied())
+                .collect()
+        })
+        .collect();
+    let order = execution_order(&dependencies).map_err(|cycle| {
+        let first = &chunks[cycle[0]].pair.start;
+        EmbedError::new(ErrorKind::DependencyCycle(
+            cycle.iter().map(|i| chunks[*i].pair.start.id()).collect(),
+        ))
+        .with_id(first.id())
+        .with_location(Location::from_byte(content, first.range().start))
+    })?;
+    Ok((order, dependencies))
+}
+
+/// Runs the function of every chunk in the file, pairing each original chunk with its result.
+/// Chunks run in dependency order, independent ones at the same time when there are jobs to spare,
+/// but are returned in the order they appear.
+fn transform_chunks(
+    content: &str,
+    file: Option<&Path>,
+    mode: RunMode,
+    options: &Options,
+    jobs: &Jobs,
+) -> Result<Vec<(Chunk, Chunk)>, EmbedError> {
+    let chunks = Config::for_path(file.unwrap_or(Path::new(".")))
+        .and_then(|config| process_to_chunks(content, file, mode, options, &config))
+        .map_err(|e| in_file(e, file))?;
+    let (order, dependencies) = chunk_order(&chunks, content).map_err(|e| in_file(e, file))?;
+    let located = |index: usize, error: EmbedError| {
+        let start = &chunks[index].pair.start;
+        in_file(error, file)
+            .with_id(start.id())
+            .with_location(Location::from_byte(content, start.range().start))
+    };
+    let transform = |index: usize| {
+        chunks[index]
+            .transform(&options.ids)
+            .map_err(|e| located(index, e))
+    };
+    let mut transformed: Vec<Option<Chunk>> = vec![None; chunks.len()];
+    match jobs.is_parallel() {
+        true => {
+            for (index, result) in run_graph(&dependencies, jobs, transform)
+                .into_iter()
+                .enumerate()
+            {
+                // The first failure in the file wins so errors don't depend on timing
+                match result {
+                    // A panic is only located here
+                    Some(result) => {
+                        transformed[index] = Some(result.map_err(|e| located(index, e))?)
+                    }
+                    None => continue,
+                }
+            }
+        }
+        false => {
+            for index in order {
+                transformed[index] = Some(transform(index)?);
+            }
         }
     }
+    Ok(chunks
+        .into_iter()
+        .zip(transformed.into_iter().flatten())
+        .collect())
 }
 
-pub fn generate(path_str: &str, id: Option<String>) {
-    let path = Path::new(path_str);
-    let files = match path.is_file() || path.is_dir() {
-        false => {
-            panic!("Path argument must point to file or directory")
+/// Renders markdown as it would be written, without touching disk. `file` is where the content
+/// lives, code runs relative to it. Content without a file, such as stdin, runs from the current
+/// directory.
+pub fn render(content: &str, file: Option<&Path>, options: &Options) -> Result<String, EmbedError> {
+    render_with(content, file, options, &Jobs::new(options.jobs))
+}
+
+fn render_with(
+    content: &str,
+    file: Option<&Path>,
+    options: &Options,
+    jobs: &Jobs,
+) -> Result<String, EmbedError> {
+    Ok(
+        transform_chunks(content, file, RunMode::Write, options, jobs)?
+            .iter()
+            .map(|(_, transformed)| transformed.print_representation())
+            .collect::<Vec<String>>()
+            .join(""),
+    )
+}
+
+/// Returns every embed in the content that is out of date, see [`check`]
+pub fn check_content(
+    content: &str,
+    file: Option<&Path>,
+    options: &Options,
+) -> Result<Vec<StaleEmbed>, EmbedError> {
+    check_content_with(content, file, options, &Jobs::new(options.jobs))
+}
+
+fn check_content_with(
+    content: &str,
+    file: Option<&Path>,
+    options: &Options,
+    jobs: &Jobs,
+) -> Result<Vec<StaleEmbed>, EmbedError> {
+    let name = file.unwrap_or(Path::new(STDIN));
+    Ok(
+        transform_chunks(content, file, RunMode::Check, options, jobs)?
+            .into_iter()
+            .filter(|(original, transformed)| original.text != transformed.text)
+            .map(|(original, transformed)| StaleEmbed {
+                file: name.to_path_buf(),
+                id: original.pair.start.id(),
+                reasons: original
+                    .pair
+                    .start
+                    .stale_reasons(&original.text, &transformed.text),
+            })
+            .collect(),
+    )
+}
+
+/// Returns a diff for every embed in the content that would change, see [`dry_run`]. Each embed is
+/// diffed on its own against the whole original, so hunk line numbers line up with the original.
+pub fn diff_content(
+    content: &str,
+    file: Option<&Path>,
+    options: &Options,
+) -> Result<Vec<EmbedDiff>, EmbedError> {
+    diff_content_with(content, file, options, &Jobs::new(options.jobs))
+}
+
+fn diff_content_with(
+    content: &str,
+    file: Option<&Path>,
+    options: &Options,
+    jobs: &Jobs,
+) -> Result<Vec<EmbedDiff>, EmbedError> {
+    let name = file.unwrap_or(Path::new(STDIN));
+    let display = name.display().to_string();
+    Ok(
+        transform_chunks(content, file, RunMode::DryRun, options, jobs)?
+            .into_iter()
+            .filter(|(original, transformed)| original.text != transformed.text)
+            .map(|(original, transformed)| {
+                let range = original.pair.internal_range();
+                let updated = format!(
+                    "{}{}{}",
+                    &content[..range.start],
+                    transformed.text,
+                    &content[range.end..]
+                );
+                let diff = TextDiff::from_lines(content, updated.as_str())
+                    .unified_diff()
+                    .header(&format!("a/{}", display), &format!("b/{}", display))
+                    .to_string();
+                EmbedDiff {
+                    file: name.to_path_buf(),
+                    id: original.pair.start.id(),
+                    diff,
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Writes the file once every embed in it has run, replacing it in one step so it is never left
+/// partly rendered
+fn process_file(
+    content: &str,
+    file: &Path,
+    options: &Options,
+    jobs: &Jobs,
+) -> Result<Vec<PathBuf>, EmbedError> {
+    let file_content = render_with(content, Some(file), options, jobs)?;
+    write_atomic(file, &file_content, options.backup)?;
+    Ok(vec![file.to_path_buf()])
+}
+
+/// Takes a file's content, the file's path, the run's options and the jobs shared by every file
+type FileProcessor<T> = fn(&str, &Path, &Options, &Jobs) -> Result<Vec<T>, EmbedError>;
+
+/// Runs `process` over every file the paths resolve to, several at once when there is more than
+/// one job. A failure in one path or file doesn't stop the others from being processed, every
+/// error encountered is returned. Results and errors are in the order of the files regardless.
+fn each_file<T: Send>(
+    paths: &[String],
+    options: &Options,
+    process: FileProcessor<T>,
+) -> Result<Vec<T>, Vec<EmbedError>> {
+    let mut files: Vec<PathBuf> = Vec::new();
+    let mut errors = Vec::new();
+    for path in paths {
+        match Config::for_path(Path::new(path))
+            .and_then(|config| collect_files(path, &config.filter(&options.filter)))
+        {
+            // A file named directly may also be found walking a directory
+            Ok(found) => files.extend(
+                found
+                    .into_iter()
+                    .filter(|f| !files.contains(f))
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => errors.push(e),
         }
-        true => match path.is_file() {
-            true => {
-                vec![PathBuf::from(path_str)]
-            }
-            false => std::fs::read_dir(path_str)
-                .unwrap()
-                .map(|res| res.unwrap().path())
-                .filter(|path| path.is_file())
-                .filter(|path| path.extension().is_some_and(|x| x == "md"))
-                .collect::<Vec<_>>(),
-        },
-    };
-    for file in files {
-        if let Ok(content) = std::fs::read_to_string(&file) {
-            process_file(&content, id.clone(), file);
-        } else {
-            panic!("Error reading file {}", file.display());
+    }
+    let jobs = Jobs::new(options.jobs);
+    let processed = jobs.map(&files, |file| {
+        std::fs::read_to_string(file)
+            .map_err(|e| EmbedError::from(e).with_file(file))
+            .and_then(|content| process(&content, file, options, &jobs))
+    });
+    let mut results = Vec::new();
+    for result in processed {
+        match result {
+            Ok(mut r) => results.append(&mut r),
+            Err(e) => errors.push(e),
         }
     }
+    match errors.is_empty() {
+        true => Ok(results),
+        false => Err(errors),
+    }
+}
+
+/// Processes each file, or every markdown file under each directory, writing the results in place
+pub fn generate(paths: &[String], options: &Options) -> Result<(), Vec<EmbedError>> {
+    each_file(paths, options, process_file).map(|_| ())
+}
+
+/// Regenerates the same files as [`generate`] without writing them, returning every embed whose
+/// content on disk is out of date. `exec-code` embeds cached by hash are compared by hash alone
+/// and are not executed.
+pub fn check(paths: &[String], options: &Options) -> Result<Vec<StaleEmbed>, Vec<EmbedError>> {
+    each_file(paths, options, |content, file, options, jobs| {
+        check_content_with(content, Some(file), options, jobs)
+    })
+}
+
+/// Runs the same files as [`generate`] but returns a unified diff for each embed that would
+/// change instead of writing them
+pub fn dry_run(paths: &[String], options: &Options) -> Result<Vec<EmbedDiff>, Vec<EmbedError>> {
+    each_file(paths, options, |content, file, options, jobs| {
+        diff_content_with(content, Some(file), options, jobs)
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunks::tests::context;
+    use embed_md_derive::RangeFn;
+    use std::sync::Mutex;
 
     #[test]
     fn new_identity_chunk_test() {
         let message = "Hello world abcd";
         let range = 0..11;
-        let chunk = new_identity_chunk(range.clone(), message);
+        let chunk = new_identity_chunk(range.clone(), message, &context(RunMode::Write));
         assert_eq!(chunk.pair.start.range(), range.start..range.start);
         assert_eq!(chunk.pair.end.range(), range.end..range.end);
         assert_eq!(chunk.text, "Hello world");
@@ -201,8 +644,8 @@ mod tests {
     fn test_new_identity_chunk() {
         let range = 0..5;
         let message = "hello";
-        let chunk = new_identity_chunk(range, message);
-        assert_eq!(chunk.transform(None).text, "hello");
+        let chunk = new_identity_chunk(range, message, &context(RunMode::Write));
+        assert_eq!(chunk.transform(&[]).unwrap().text, "hello");
         assert_eq!(chunk.text, "hello");
     }
 
@@ -210,8 +653,8 @@ mod tests {
     fn test_chunk_run() {
         let range = 0..5;
         let message = "hello";
-        let chunk = new_identity_chunk(range, message);
-        let x = chunk.transform(None);
+        let chunk = new_identity_chunk(range, message, &context(RunMode::Write));
+        let x = chunk.transform(&[]).unwrap();
         assert_eq!(x.text, "hello");
     }
 
@@ -219,8 +662,548 @@ mod tests {
     fn test_chunk_print_representation() {
         let range = 0..5;
         let message = "hello";
-        let chunk = new_identity_chunk(range, message);
-        let x = chunk.transform(None);
+        let chunk = new_identity_chunk(range, message, &context(RunMode::Write));
+        let x = chunk.transform(&[]).unwrap();
         assert_eq!(x.print_representation(), "hello");
     }
+
+    #[test]
+    fn test_process_to_chunks_missing_end_tag() {
+        let message = "# Title\n<!--embed identity id=\"lonely\" -->\ntext\n";
+        let error = process_to_chunks(
+            message,
+            Some(Path::new("doc.md")),
+            RunMode::Write,
+            &Options::default(),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::MissingEndTag));
+        assert_eq!(error.id.as_deref(), Some("lonely"));
+        assert_eq!(
+            error.location,
+            Some(Location {
+                byte: 8,
+                line: 2,
+                column: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_process_to_chunks_duplicate_tag() {
+        let message = r#"<!--embed identity id="twice" -->
+<!--embed identity id="twice" /-->
+<!--embed identity id="twice" /-->
+"#;
+        let error = process_to_chunks(
+            message,
+            Some(Path::new("doc.md")),
+            RunMode::Write,
+            &Options::default(),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::DuplicateTag(3)));
+    }
+
+    #[test]
+    fn test_check_content() {
+        let message = r#"<!--embed exec-code id="fresh" cache="always" -->
+<!--embed-meta hash="8vvEKPZodcXrEwLYFaxx4awckueP71/FMBORCCARxvw": last_run="1111" -->
+```shell
+echo "fresh"
+```
+<!-- result -->
+```
+not what echo says
+```
+<!--embed exec-code id="fresh" /-->
+<!--embed exec-code id="stale" cache="hash": out_dir="../test_out_dir" -->
+<!--embed-meta hash="outdated": last_run="1111" -->
+```shell
+echo "stale"
+```
+<!--embed exec-code id="stale" /-->
+"#;
+        let stale =
+            check_content(message, Some(Path::new("Cargo.toml")), &Options::default()).unwrap();
+        assert_eq!(
+            stale,
+            vec![StaleEmbed {
+                file: PathBuf::from("Cargo.toml"),
+                id: "stale".to_string(),
+                reasons: vec!["code, env or an embed it reads changed".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_content() {
+        let message = r#"# Title
+<!--embed exec-code id="changed" o_lang="none" -->
+```shell
+echo "changed"
+```
+<!--embed exec-code id="changed" /-->
+Trailing text
+"#;
+        let diffs =
+            diff_content(message, Some(Path::new("Cargo.toml")), &Options::default()).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].id, "changed");
+        let re = regex::Regex::new(r#"last_run="\d+""#).unwrap();
+        assert_eq!(
+            re.replace(&diffs[0].diff, r#"last_run="1111""#),
+            r#"--- a/Cargo.toml
++++ b/Cargo.toml
+@@ -1,7 +1,9 @@
+ # Title
+ <!--embed exec-code id="changed" o_lang="none" -->
++<!--embed-meta hash="cD37Gd8EDNCtOt4FSyxRYghFHhGouS5UmS/Moe/NFjk": last_run="1111" -->
+ ```shell
+ echo "changed"
+ ```
++<!-- result -->
+ <!--embed exec-code id="changed" /-->
+ Trailing text
+"#
+        );
+    }
+
+    #[test]
+    fn test_render_without_file() {
+        let message = r#"<!--embed exec-code id="stdin" o_lang="none": out_dir="../test_out_dir" -->
+```shell
+echo "from stdin"
+```
+<!--embed exec-code id="stdin" /-->
+"#;
+        let rendered = render(message, None, &Options::default()).unwrap();
+        assert!(rendered.contains("<!-- result -->"));
+        assert!(rendered.starts_with(r#"<!--embed exec-code id="stdin""#));
+    }
+
+    #[test]
+    fn test_each_file_dedupes_paths() {
+        let paths = vec![
+            "../samples/sample.md".to_string(),
+            "../samples".to_string(),
+            "../missing".to_string(),
+        ];
+        let result = each_file(&paths, &Options::default(), |_, file, _, _| {
+            Ok(vec![file.to_path_buf()])
+        });
+        let errors = result.unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(*errors[0].kind, ErrorKind::InvalidPath));
+
+        let result = each_file(&paths[..2], &Options::default(), |_, file, _, _| {
+            Ok(vec![file.to_path_buf()])
+        });
+        assert_eq!(
+            result.unwrap(),
+            vec![
+                PathBuf::from("../samples/sample.md"),
+                PathBuf::from("../samples/another.md")
+            ]
+        );
+    }
+
+    /// The params the first `exec-code` tag in the message is built with
+    fn exec_params(message: &str, options: &Options, config: &Config) -> HashMap<String, String> {
+        let built = Arc::new(Mutex::new(Vec::new()));
+        let record = built.clone();
+        let mut functions = FunctionRegistry::default();
+        functions.register("exec-code", move |args: FunctionArgs| {
+            record.lock().unwrap().push(args.params.clone());
+            Ok(Box::new(Identity::new(args.id, args.is_end, args.range)))
+        });
+        let options = Options {
+            functions,
+            ..options.clone()
+        };
+        process_to_chunks(
+            message,
+            Some(Path::new("doc.md")),
+            RunMode::Write,
+            &options,
+            config,
+        )
+        .unwrap();
+        let params = built.lock().unwrap().remove(0);
+        params
+    }
+
+    #[test]
+    fn test_process_to_chunks_defaults() {
+        let message = r#"<!--embed exec-code id="a" timeout="5s" -->
+```shell
+ls
+```
+<!--embed exec-code id="a" /-->
+"#;
+        let mut options = Options::default();
+        options
+            .defaults
+            .insert("timeout".to_string(), "30s".to_string());
+        options
+            .defaults
+            .insert("cache".to_string(), "hash".to_string());
+        let params = exec_params(message, &options, &Config::default());
+        assert_eq!(
+            params.get("timeout").unwrap(),
+            "30s",
+            "--timeout beats the tag"
+        );
+        assert_eq!(params.get("cache").unwrap(), "hash");
+    }
+
+    #[test]
+    fn test_process_to_chunks_document_defaults() {
+        let message = r#"<!--embed-defaults cache="hash": o_lang="text" -->
+<!--embed exec-code id="a" o_lang="json" -->
+```shell
+ls
+```
+<!--embed exec-code id="a" /-->
+<!--embed-defaults timeout="1m" -->
+"#;
+        let config = Config {
+            cache: Some("always".to_string()),
+            timeout: Some("30s".to_string()),
+            out_dir: Some("project".to_string()),
+            ..Config::default()
+        };
+        let mut options = Options::default();
+        options.defaults.insert("ttl".to_string(), "1h".to_string());
+        let params = exec_params(message, &options, &config);
+        assert_eq!(params.get("ttl").unwrap(), "1h");
+        assert_eq!(params.get("cache").unwrap(), "hash");
+        assert_eq!(params.get("o_lang").unwrap(), "json");
+        assert_eq!(params.get("timeout").unwrap(), "1m");
+        assert_eq!(params.get("out_dir").unwrap(), "project");
+    }
+
+    #[test]
+    fn test_process_to_chunks_invalid_defaults() {
+        let message = "text\n<!--embed-defaults cache=\"hash\" o_lang=text -->\n";
+        let error = process_to_chunks(
+            message,
+            Some(Path::new("doc.md")),
+            RunMode::Write,
+            &Options::default(),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::MalformedTag(_)));
+        assert_eq!(
+            in_file(error, Some(Path::new("doc.md"))).to_string(),
+            "doc.md:2:33: malformed tag: the value of 'o_lang' should be quoted"
+        );
+    }
+
+    #[test]
+    fn test_process_to_chunks_env() {
+        let message = r#"<!--embed-config env
+SHARED=document
+DOCUMENT_ONLY="from the document"
+-->
+<!--embed exec-code id="a" env="SHARED=tag,TAG=tag,CLI=tag" -->
+```shell
+env
+```
+<!--embed exec-code id="a" /-->
+"#;
+        let options = Options {
+            env: vec![("CLI".to_string(), "cli".to_string())],
+            ..Options::default()
+        };
+        let chunks =
+            process_to_chunks(message, None, RunMode::Write, &options, &Config::default()).unwrap();
+        let env = |key: &str| chunks[1].context.env.get(key).map(String::as_str);
+        assert_eq!(env("SHARED"), Some("tag"));
+        assert_eq!(env("DOCUMENT_ONLY"), Some("from the document"));
+        assert_eq!(env("TAG"), Some("tag"));
+        assert_eq!(env("CLI"), Some("cli"));
+        // Text outside of embeds doesn't run anything
+        assert!(chunks[0].context.env.is_empty());
+
+        let message = format!(
+            "````markdown\n<!--embed-config env\nEXAMPLE=1\n-->\n````\n{}",
+            message
+        );
+        let chunks =
+            process_to_chunks(&message, None, RunMode::Write, &options, &Config::default())
+                .unwrap();
+        let env = &chunks[1].context.env;
+        assert_eq!(env["DOCUMENT_ONLY"], "from the document");
+        assert!(
+            !env.contains_key("EXAMPLE"),
+            "an example in a code block sets nothing"
+        );
+    }
+
+    #[test]
+    fn test_process_to_chunks_context() {
+        let message = r#"<!--embed exec-code id="a" out_dir="/tmp/outputs" -->
+```shell
+ls
+```
+<!--embed exec-code id="a" /-->
+"#;
+        let file = Path::new("docs/doc.md");
+        let options = Options {
+            force: true,
+            ..Options::default()
+        };
+        let params = exec_params(message, &options, &Config::default());
+        assert_eq!(
+            params.keys().collect::<Vec<_>>(),
+            vec!["out_dir"],
+            "only the tag's params"
+        );
+        let chunks = process_to_chunks(
+            message,
+            Some(file),
+            RunMode::Check,
+            &options,
+            &Config::default(),
+        )
+        .unwrap();
+        let context = &chunks[1].context;
+        assert_eq!(context.source.as_deref(), Some(file));
+        assert_eq!(context.root, Path::new("docs"));
+        assert_eq!(context.mode, RunMode::Check);
+        assert!(context.force);
+        assert!(context.config::<Config>().is_some());
+        assert!(context.outputs.path("a").starts_with("/tmp/outputs"));
+    }
+
+    #[test]
+    fn test_process_to_chunks_invalid_env() {
+        let message = "<!--embed-config env\nNOT_A_PAIR\n-->\n";
+        let error = process_to_chunks(
+            message,
+            None,
+            RunMode::Write,
+            &Options::default(),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::InvalidParam(ref p, _) if p == "env"));
+        assert_eq!(error.location.unwrap().line, 1);
+    }
+
+    /// The code block of the result of an embed
+    fn result_of(rendered: &str, id: &str) -> String {
+        let start = rendered
+            .find(&format!(r#"<!--embed exec-code id="{}" "#, id))
+            .unwrap();
+        let result = &rendered[start..];
+        let result = &result[result.find("<!-- result -->\n").unwrap()..];
+        result[..result.find("<!--embed exec-code").unwrap()].to_string()
+    }
+
+    #[test]
+    fn test_render_dependencies_run_first() {
+        let message = r#"<!--embed exec-code id="dependency_reader" out_dir="../test_out_dir" -->
+```shell
+cat $$dependency_writer$$
+```
+<!--embed exec-code id="dependency_reader" /-->
+<!--embed exec-code id="dependency_writer" out_dir="../test_out_dir" -->
+```shell
+date +%s%N
+```
+<!--embed exec-code id="dependency_writer" /-->
+"#;
+        let rendered = render(message, Some(Path::new("Cargo.toml")), &Options::default()).unwrap();
+        assert_eq!(
+            result_of(&rendered, "dependency_reader"),
+            result_of(&rendered, "dependency_writer")
+        );
+        assert!(rendered.starts_with(r#"<!--embed exec-code id="dependency_reader""#));
+    }
+
+    #[test]
+    fn test_render_parallel() {
+        let sleeper = |id: &str| {
+            format!(
+                r#"<!--embed exec-code id="{id}" out_dir="../test_out_dir" -->
+```shell
+sleep 0.5; echo "{id} $(date +%s%N)"
+```
+<!--embed exec-code id="{id}" /-->
+"#
+            )
+        };
+        let message = format!(
+            r#"{}<!--embed exec-code id="parallel_reader" out_dir="../test_out_dir" -->
+```shell
+cat $$parallel_c$$
+```
+<!--embed exec-code id="parallel_reader" /-->
+{}{}"#,
+            sleeper("parallel_a"),
+            sleeper("parallel_b"),
+            sleeper("parallel_c"),
+        );
+        let options = Options {
+            jobs: 4,
+            ..Options::default()
+        };
+        let start = std::time::Instant::now();
+        let rendered = render(&message, Some(Path::new("Cargo.toml")), &options).unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_millis(1400));
+        assert_eq!(
+            result_of(&rendered, "parallel_reader"),
+            result_of(&rendered, "parallel_c")
+        );
+        let ids: Vec<&str> = regex::Regex::new(r#"<!--embed exec-code id="(\w+)" out_dir"#)
+            .unwrap()
+            .captures_iter(&rendered)
+            .map(|c| c.get(1).unwrap().as_str())
+            .collect();
+        assert_eq!(
+            ids,
+            vec!["parallel_a", "parallel_reader", "parallel_b", "parallel_c"]
+        );
+    }
+
+    #[derive(RangeFn, Debug)]
+    struct Panics {
+        id: String,
+        range: Range<usize>,
+    }
+
+    impl FunctionTag for Panics {
+        fn transform(&self, _text: String, _context: &Context) -> Result<String, EmbedError> {
+            panic!("{} gave up", self.id)
+        }
+    }
+
+    #[test]
+    fn test_render_parallel_panic() {
+        let mut functions = FunctionRegistry::default();
+        functions.register("panics", |args: FunctionArgs| {
+            Ok(Box::new(Panics {
+                id: args.id,
+                range: args.range,
+            }))
+        });
+        let options = Options {
+            functions,
+            jobs: 2,
+            ..Options::default()
+        };
+        let message = r#"
+<!--embed panics id="a" -->
+<!--embed panics id="a" /-->
+<!--embed identity id="b" -->
+$$a$$
+<!--embed identity id="b" /-->
+"#;
+        let error = render(message, Some(Path::new("doc.md")), &options).unwrap_err();
+        assert_eq!(error.to_string(), "doc.md:2:1: [a] panicked: a gave up");
+    }
+
+    #[test]
+    fn test_render_dependency_cycle() {
+        let message = r#"# Title
+<!--embed exec-code id="a" -->
+```shell
+cat $$b$$
+```
+<!--embed exec-code id="a" /-->
+<!--embed exec-code id="b" -->
+```shell
+cat $$c$$ $$a$$
+```
+<!--embed exec-code id="b" /-->
+<!--embed exec-code id="c" -->
+```shell
+echo "c"
+```
+<!--embed exec-code id="c" /-->
+"#;
+        let error = render(message, Some(Path::new("doc.md")), &Options::default()).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::DependencyCycle(_)));
+        assert_eq!(
+            error.to_string(),
+            "doc.md:2:1: [a] dependency cycle: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn test_process_to_chunks_unknown_function() {
+        let message = "\n\n<!--embed nope id=\"unknown\" -->\n<!--embed nope id=\"unknown\" /-->\n";
+        let error = process_to_chunks(
+            message,
+            Some(Path::new("doc.md")),
+            RunMode::Write,
+            &Options::default(),
+            &Config::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::UnknownFunction(ref f) if f == "nope"));
+        assert_eq!(error.location.unwrap().line, 3);
+        assert_eq!(
+            error.with_file("doc.md").to_string(),
+            "doc.md:3:1: [unknown] 'nope' is not a known function"
+        );
+    }
+
+    #[derive(EmbedParams)]
+    struct ShoutParams {
+        suffix: Option<String>,
+    }
+
+    #[derive(RangeFn, Debug)]
+    struct Shout {
+        id: String,
+        suffix: String,
+        range: Range<usize>,
+    }
+
+    impl FunctionTag for Shout {
+        fn transform(&self, text: String, _context: &Context) -> Result<String, EmbedError> {
+            Ok(text
+                .to_uppercase()
+                .replace('\n', &format!("{}\n", self.suffix)))
+        }
+    }
+
+    #[test]
+    fn test_typed_function_ignores_unknown_defaults() {
+        let mut functions = FunctionRegistry::default();
+        functions.register_with_params::<ShoutParams, _>("shout", |args: FunctionArgs| {
+            let params = ShoutParams::from_params(&args.params)?;
+            Ok(Box::new(Shout {
+                id: args.id,
+                suffix: params.suffix.unwrap_or_default(),
+                range: args.range,
+            }))
+        });
+        let mut options = Options {
+            functions,
+            ..Options::default()
+        };
+        options
+            .defaults
+            .insert("timeout".to_string(), "30s".to_string());
+        let message = r#"<!--embed-defaults cache="hash" -->
+<!--embed shout id="a" suffix="!" -->
+hi
+<!--embed shout id="a" /-->
+"#;
+        let rendered = render(message, None, &options).unwrap();
+        assert!(rendered.contains("-->\nHI!\n<!--embed shout"));
+
+        // The tag itself is still checked
+        let message =
+            "<!--embed shout id=\"a\" cache=\"hash\" -->\nhi\n<!--embed shout id=\"a\" /-->\n";
+        let error = render(message, None, &options).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::UnknownParam(ref p, _) if p == "cache"));
+    }
 }
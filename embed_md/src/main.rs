@@ -1,23 +1,271 @@
-use clap::Parser;
-use embed_md::generate;
+use std::io::Read;
+
+use clap::{Parser, Subcommand};
+use std::collections::HashMap;
+use std::path::Path;
+
+use embed_md::{
+    check, check_content, diff_content, dry_run, generate, read_env_file, render, watch, Config,
+    EmbedDiff, EmbedError, ErrorKind, FileFilter, FunctionRegistry, Options, StaleEmbed,
+    WatchEvent, CONFIG_FILE, STDIN,
+};
 
 fn main() {
     let args = Args::parse();
-    let id = match args.id {
-        None => None,
-        Some(x) if x.is_empty() => None,
-        Some(_) => Some(args.id.clone().unwrap()),
+    let mut defaults = HashMap::new();
+    if let Some(timeout) = args.timeout {
+        defaults.insert("timeout".to_string(), timeout);
+    }
+    // Variables given with --env win over those from the file
+    let mut env = Vec::new();
+    if let Some(env_file) = &args.env_file {
+        match read_env_file(Path::new(env_file)) {
+            Ok(file_env) => env.extend(file_env),
+            Err(e) => exit_with_errors(vec![e]),
+        }
+    }
+    env.extend(args.env);
+    let options = Options {
+        ids: args.id.into_iter().filter(|id| !id.is_empty()).collect(),
+        filter: FileFilter {
+            include: args.include,
+            exclude: args.exclude,
+        },
+        defaults,
+        env,
+        jobs: args.jobs,
+        backup: args.backup,
+        force: args.force,
+        functions: FunctionRegistry::default(),
     };
-    generate(args.path.as_str(), id);
+    if let Some(command) = &args.command {
+        if args.check || args.dry_run || args.print_config {
+            eprintln!(
+                "error: {} can't be combined with --check, --dry-run or --print-config, a path \
+                 named {} goes after --, as in `embed_md --check -- {}`",
+                command.name(),
+                command.name(),
+                command.name()
+            );
+            std::process::exit(2);
+        }
+    }
+    if let Some(Command::Functions { name }) = &args.command {
+        print_functions(&options.functions, name.as_deref());
+    } else if let Some(Command::Watch { paths }) = &args.command {
+        if let Err(e) = watch(paths, &options, report_watch) {
+            exit_with_errors(vec![e]);
+        }
+    } else if args.print_config {
+        print_config(&args.paths, &options);
+    } else if args.paths.iter().any(|p| p == STDIN) {
+        if args.paths.len() > 1 {
+            eprintln!("error: {} can't be combined with other paths", STDIN);
+            std::process::exit(2);
+        }
+        let mut content = String::new();
+        if let Err(e) = std::io::stdin().read_to_string(&mut content) {
+            exit_with_errors(vec![e.into()]);
+        }
+        if args.check {
+            report_stale(check_content(&content, None, &options).map_err(|e| vec![e]));
+        } else if args.dry_run {
+            report_diffs(diff_content(&content, None, &options).map_err(|e| vec![e]));
+        } else {
+            match render(&content, None, &options) {
+                Ok(rendered) => print!("{}", rendered),
+                Err(e) => exit_with_errors(vec![e]),
+            }
+        }
+    } else if args.check {
+        report_stale(check(&args.paths, &options));
+    } else if args.dry_run {
+        report_diffs(dry_run(&args.paths, &options));
+    } else if let Err(errors) = generate(&args.paths, &options) {
+        exit_with_errors(errors);
+    }
+}
+
+/// Prints the config each path runs with, stdin uses the config for the current directory
+fn print_config(paths: &[String], options: &Options) {
+    for path in paths {
+        let dir = match path.as_str() {
+            STDIN => ".",
+            path => path,
+        };
+        match Config::for_path(Path::new(dir)) {
+            Ok(config) => {
+                let source = match &config.path {
+                    Some(file) => file.display().to_string(),
+                    None => format!("no {} found", CONFIG_FILE),
+                };
+                println!("# {}: {}", path, source);
+                match toml::to_string(&config.resolve(options)) {
+                    Ok(resolved) => print!("{}", resolved),
+                    Err(e) => exit_with_errors(vec![EmbedError::exec(e.to_string())]),
+                }
+            }
+            Err(e) => exit_with_errors(vec![e]),
+        }
+    }
+}
+
+/// Lists the functions embeds can use, or the params of one of them
+fn print_functions(functions: &FunctionRegistry, name: Option<&str>) {
+    match name {
+        None => functions
+            .names()
+            .iter()
+            .for_each(|name| println!("{}", name)),
+        Some(name) if !functions.contains(name) => exit_with_errors(vec![EmbedError::new(
+            ErrorKind::UnknownFunction(name.to_string()),
+        )]),
+        Some(name) => match functions.params(name) {
+            Some(params) => params.iter().for_each(|param| println!("{}", param)),
+            None => println!("{} takes no documented params", name),
+        },
+    }
+}
+
+fn report_stale(result: Result<Vec<StaleEmbed>, Vec<EmbedError>>) {
+    match result {
+        Ok(stale) if stale.is_empty() => {}
+        Ok(stale) => {
+            for embed in stale {
+                match embed.reasons.is_empty() {
+                    true => eprintln!("stale: {} [{}]", embed.file.display(), embed.id),
+                    false => eprintln!(
+                        "stale: {} [{}]: {}",
+                        embed.file.display(),
+                        embed.id,
+                        embed.reasons.join(", ")
+                    ),
+                }
+            }
+            std::process::exit(1);
+        }
+        Err(errors) => exit_with_errors(errors),
+    }
+}
+
+fn report_watch(event: WatchEvent) {
+    match event {
+        WatchEvent::Rendered { file, ids } => {
+            println!("rendered: {} [{}]", file.display(), ids.join(", "))
+        }
+        WatchEvent::Failed(error) => eprintln!("error: {}", error),
+    }
+}
+
+fn report_diffs(result: Result<Vec<EmbedDiff>, Vec<EmbedError>>) {
+    match result {
+        Ok(diffs) => {
+            for diff in diffs {
+                println!("# {} [{}]", diff.file.display(), diff.id);
+                print!("{}", diff.diff);
+            }
+        }
+        Err(errors) => exit_with_errors(errors),
+    }
+}
+
+fn exit_with_errors(errors: Vec<EmbedError>) {
+    for error in errors {
+        eprintln!("error: {}", error);
+    }
+    std::process::exit(1);
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    // ID of the embedding to run. If omitted runs all
-    #[arg(short, long)]
-    id: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // ID of the embedding to run, may be repeated. If omitted runs all
+    #[arg(short, long, global = true)]
+    id: Vec<String>,
+
+    /// Exit non-zero listing every embed that is out of date instead of rewriting files
+    #[arg(long, conflicts_with = "dry_run")]
+    check: bool,
 
+    /// Print a unified diff of each embed that would change instead of rewriting files. Code runs
+    /// but its outputs and timestamps aren't kept
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Only process files under the path matching this glob, may be repeated
+    #[arg(long, value_name = "GLOB", global = true)]
+    include: Vec<String>,
+
+    /// Skip files under the path matching this glob, may be repeated
+    #[arg(long, value_name = "GLOB", global = true)]
+    exclude: Vec<String>,
+
+    /// Timeout for every exec-code embed, including those that set their own, such as 30s or 5m
+    #[arg(long, value_name = "DURATION", global = true)]
+    timeout: Option<String>,
+
+    /// Run every embed, even those whose cache says the result is current
+    #[arg(long)]
+    force: bool,
+
+    /// Copy each file to <file>.bak before rewriting it
+    #[arg(long, conflicts_with_all = ["check", "dry_run"])]
+    backup: bool,
+
+    /// How many embeds to run at once, embeds still wait for those they read through $$id$$
+    #[arg(short, long, value_name = "N", default_value_t = 1, global = true)]
+    jobs: usize,
+
+    /// Print the settings each path runs with after layering the command line over the nearest
+    /// embed_md.toml, instead of processing anything
+    #[arg(long)]
+    print_config: bool,
+
+    /// Set an environment variable for every exec-code embed, may be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env_arg, global = true)]
+    env: Vec<(String, String)>,
+
+    /// Load environment variables for every exec-code embed from a .env file
+    #[arg(long, value_name = "PATH", global = true)]
+    env_file: Option<String>,
+
+    /// Files or directories to process, `-` reads markdown from stdin and writes it to stdout. A
+    /// path with the same name as a command, such as `watch`, goes after `--`
     #[arg(default_value = "./")]
-    path: String,
+    paths: Vec<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the functions embeds can use, or the params of one of them
+    Functions {
+        /// The function to list the params of
+        name: Option<String>,
+    },
+    /// Keep running, re-running the embeds affected whenever a markdown file or a file an embed
+    /// depends_on changes
+    Watch {
+        /// Files or directories to watch
+        #[arg(default_value = "./")]
+        paths: Vec<String>,
+    },
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Functions { .. } => "functions",
+            Command::Watch { .. } => "watch",
+        }
+    }
+}
+
+fn parse_env_arg(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
 }
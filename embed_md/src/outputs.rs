@@ -0,0 +1,41 @@
+use std::env::current_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use embed_md_traits::OutputStore;
+
+/// Keeps outputs as files in a directory, named after the document and the embed. Outputs of
+/// content without a file, such as stdin, are named after the directory it runs from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputDir {
+    dir: String,
+    namespace: String,
+}
+
+impl OutputDir {
+    /// `dir` may start with `~`, a relative one is relative to the current directory
+    pub fn new(dir: &str, source: Option<&Path>, root: &Path) -> Self {
+        let absolute = |path: &Path| {
+            fs::canonicalize(path).unwrap_or_else(|_| current_dir().unwrap_or_default().join(path))
+        };
+        let source = match source {
+            Some(source) => absolute(source),
+            None => absolute(root).join("-"),
+        };
+        OutputDir {
+            dir: shellexpand::tilde(dir).to_string(),
+            namespace: general_purpose::URL_SAFE_NO_PAD
+                .encode(Sha256::digest(source.to_string_lossy().as_bytes())),
+        }
+    }
+}
+
+impl OutputStore for OutputDir {
+    fn path(&self, id: &str) -> PathBuf {
+        PathBuf::from(format!("{}/{}_{}.out", self.dir, self.namespace, id))
+    }
+}
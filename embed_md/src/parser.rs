@@ -0,0 +1,902 @@
+use std::ops::Range;
+
+use embed_md_traits::{EmbedError, ErrorKind, Location};
+
+/// Starts every tag. Comments starting `<!--embed-`, such as `<!--embed-meta`, aren't tags and are
+/// left in the text.
+const TAG_START: &str = "<!--embed";
+const TAG_END: &str = "-->";
+/// Ends a closing tag
+const END_MARKER: &str = "/-->";
+
+/// A byte range of the source along with the line and column it starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    /// Counted in characters rather than bytes
+    pub column: usize,
+}
+
+impl Span {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    pub fn location(&self) -> Location {
+        Location {
+            byte: self.start,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// Markdown outside of tags, including any tag that couldn't be parsed
+    Text,
+    /// `<!--embed`
+    Open,
+    /// The function a tag runs, such as `exec-code`
+    Function(String),
+    /// `key="value"` with any escapes resolved, a bare `key` has the value `true`
+    Attribute { key: String, value: String },
+    /// The `/` that marks a closing tag
+    EndMarker,
+    /// `-->` along with the line break after it
+    Close,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+/// Why part of the source couldn't be parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl From<Diagnostic> for EmbedError {
+    fn from(diagnostic: Diagnostic) -> Self {
+        EmbedError::new(ErrorKind::MalformedTag(diagnostic.message))
+            .with_location(diagnostic.span.location())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub key: String,
+    pub value: String,
+    pub span: Span,
+}
+
+/// An `<!--embed function id="..." -->` tag, `/-->` making it a closing tag
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub function: String,
+    pub id: String,
+    /// Every attribute but the id, in the order they were written
+    pub attributes: Vec<Attribute>,
+    pub is_end: bool,
+    /// From `<!--embed` through the line break after `-->`
+    pub span: Span,
+}
+
+/// A comment such as `<!--embed-defaults cache="hash" -->` that configures embeds rather than
+/// running a function
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    pub attributes: Vec<Attribute>,
+    /// From `<!--embed-` through `-->`
+    pub span: Span,
+}
+
+/// A comment such as `<!--embed-config env ... -->` whose body is read as is rather than as
+/// attributes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    /// Everything between the name and `-->`
+    pub body: String,
+    /// From `<!--embed-` through `-->`
+    pub span: Span,
+}
+
+/// Byte offsets of the start of each line, to find the line and column of a byte without
+/// rescanning the source
+struct Lines<'a> {
+    source: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> Lines<'a> {
+    fn new(source: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Lines { source, starts }
+    }
+
+    fn span(&self, range: Range<usize>) -> Span {
+        let line = self.starts.partition_point(|start| *start <= range.start);
+        let line_start = self.starts[line - 1];
+        Span {
+            start: range.start,
+            end: range.end,
+            line,
+            column: self.source[line_start..range.start].chars().count() + 1,
+        }
+    }
+}
+
+/// Byte ranges of the fenced code blocks in markdown, an unclosed one runs to the end
+fn fenced_code(source: &str) -> Vec<Range<usize>> {
+    let mut fences = Vec::new();
+    let mut open: Option<(usize, char, usize)> = None;
+    let mut line_start = 0;
+    for line in source.split_inclusive('\n') {
+        let line_end = line_start + line.len();
+        let trimmed = line.trim_start_matches(' ');
+        if let Some(c) = trimmed.chars().next().filter(|c| *c == '`' || *c == '~') {
+            let fence = trimmed.len() - trimmed.trim_start_matches(c).len();
+            match open {
+                None if fence >= 3 => open = Some((line_start, c, fence)),
+                Some((start, open_char, open_fence))
+                    if c == open_char
+                        && fence >= open_fence
+                        && trimmed[fence..].trim().is_empty() =>
+                {
+                    fences.push(start..line_end);
+                    open = None;
+                }
+                _ => {}
+            }
+        }
+        line_start = line_end;
+    }
+    if let Some((start, ..)) = open {
+        fences.push(start..source.len());
+    }
+    fences
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    lines: Lines<'a>,
+    fences: Vec<Range<usize>>,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Lexer {
+            source,
+            lines: Lines::new(source),
+            fences: fenced_code(source),
+        }
+    }
+
+    /// The end of the fenced code block `at` is in, comments in code blocks are examples that are
+    /// left as text
+    fn fence_end(&self, at: usize) -> Option<usize> {
+        self.fences
+            .iter()
+            .find(|fence| fence.contains(&at))
+            .map(|fence| fence.end)
+    }
+
+    fn token(&self, kind: TokenKind, range: Range<usize>) -> Token {
+        Token {
+            kind,
+            span: self.lines.span(range),
+        }
+    }
+
+    fn diagnostic(&self, message: String, range: Range<usize>) -> Diagnostic {
+        Diagnostic {
+            message,
+            span: self.lines.span(range),
+        }
+    }
+
+    /// The range of the character at `at`, empty at the end of the source
+    fn char_at(&self, at: usize) -> Range<usize> {
+        at..at + self.source[at..].chars().next().map_or(0, char::len_utf8)
+    }
+
+    fn skip_whitespace(&self, at: usize) -> usize {
+        let rest = &self.source[at..];
+        at + rest.len() - rest.trim_start().len()
+    }
+
+    /// Reads the tag starting at `start`, returning its tokens and where it ends
+    fn tag(&self, start: usize) -> Result<(Vec<Token>, usize), Diagnostic> {
+        let source = self.source;
+        let mut at = start + TAG_START.len();
+        let mut tokens = vec![self.token(TokenKind::Open, start..at)];
+        at = self.skip_whitespace(at);
+        let name_end = source[at..]
+            .find(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '=')
+            .map_or(source.len(), |i| at + i);
+        let name_end = [TAG_END, END_MARKER]
+            .iter()
+            .filter_map(|end| source[at..name_end].find(end))
+            .min()
+            .map_or(name_end, |i| at + i);
+        if name_end == at {
+            return Err(self.diagnostic(
+                "expected a function name after <!--embed".to_string(),
+                self.char_at(at),
+            ));
+        }
+        tokens.push(self.token(
+            TokenKind::Function(source[at..name_end].to_string()),
+            at..name_end,
+        ));
+        let (attributes, mut at) = self.attributes(name_end, start..start + TAG_START.len())?;
+        tokens.extend(attributes);
+        if source[at..].starts_with(END_MARKER) {
+            tokens.push(self.token(TokenKind::EndMarker, at..at + 1));
+            at += 1;
+        }
+        let end = self.line_break(at + TAG_END.len())?;
+        tokens.push(self.token(TokenKind::Close, at..end));
+        Ok((tokens, end))
+    }
+
+    /// Reads attributes from `at` up to the `-->` or `/-->` that closes the comment opened at
+    /// `open`, returning their tokens and where the close starts. Attributes are separated by
+    /// whitespace, a `:` is accepted as a separator too as older tags use `": "`.
+    fn attributes(
+        &self,
+        mut at: usize,
+        open: Range<usize>,
+    ) -> Result<(Vec<Token>, usize), Diagnostic> {
+        let mut tokens = Vec::new();
+        let mut separated = false;
+        loop {
+            let skipped = self.skip_whitespace(at);
+            separated |= skipped != at;
+            at = skipped;
+            let rest = &self.source[at..];
+            if rest.is_empty() {
+                return Err(self.diagnostic(
+                    format!("{} is never closed with -->", &self.source[open.clone()]),
+                    open,
+                ));
+            }
+            if rest.starts_with(TAG_END) || rest.starts_with(END_MARKER) {
+                return Ok((tokens, at));
+            }
+            if !separated {
+                return Err(self.diagnostic(
+                    "expected whitespace before the attribute".to_string(),
+                    self.char_at(at),
+                ));
+            }
+            let (token, end) = self.attribute(at)?;
+            tokens.push(token);
+            at = end;
+            separated = self.source[at..].starts_with(':');
+            if separated {
+                at += 1;
+            }
+        }
+    }
+
+    /// Reads `key="value"`, `key='value'` or a bare `key`, which is a flag set to `true`. A
+    /// backslash escapes a quote, a backslash, `n` for a line break or `t` for a tab, any other
+    /// backslash is kept as is.
+    fn attribute(&self, start: usize) -> Result<(Token, usize), Diagnostic> {
+        let source = self.source;
+        let key_end = source[start..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(source.len(), |i| start + i);
+        if key_end == start {
+            let unexpected = self.char_at(start);
+            return Err(self.diagnostic(
+                format!("unexpected '{}' in the tag", &source[unexpected.clone()]),
+                unexpected,
+            ));
+        }
+        let key = &source[start..key_end];
+        let attribute = |value: String, end: usize| {
+            let kind = TokenKind::Attribute {
+                key: key.to_string(),
+                value,
+            };
+            Ok((self.token(kind, start..end), end))
+        };
+        let rest = &source[key_end..];
+        if rest.is_empty()
+            || rest.starts_with(|c: char| c.is_whitespace() || c == ':')
+            || rest.starts_with(TAG_END)
+            || rest.starts_with(END_MARKER)
+        {
+            return attribute("true".to_string(), key_end);
+        }
+        if !rest.starts_with('=') {
+            let unexpected = self.char_at(key_end);
+            return Err(self.diagnostic(
+                format!(
+                    "unexpected '{}' after '{}'",
+                    &source[unexpected.clone()],
+                    key
+                ),
+                start..unexpected.end,
+            ));
+        }
+        let value_start = key_end + 1;
+        let quote = match source[value_start..].chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => {
+                return Err(self.diagnostic(
+                    format!("the value of '{}' should be quoted", key),
+                    start..self.char_at(value_start).end,
+                ))
+            }
+        };
+        let mut value = String::new();
+        let mut chars = source[value_start + 1..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped @ ('"' | '\'' | '\\'))) => value.push(escaped),
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, other)) => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                    None => break,
+                },
+                c if c == quote => return attribute(value, value_start + 1 + i + 1),
+                c => value.push(c),
+            }
+        }
+        Err(self.diagnostic(
+            format!("the value of '{}' is never closed with {}", key, quote),
+            start..value_start + 1,
+        ))
+    }
+
+    /// Tags take up whole lines, only spaces may follow `-->` before the line break
+    fn line_break(&self, at: usize) -> Result<usize, Diagnostic> {
+        let rest = &self.source[at..];
+        let trailing = rest.len() - rest.trim_start_matches([' ', '\t']).len();
+        let rest = &rest[trailing..];
+        match rest {
+            "" => Ok(self.source.len()),
+            _ if rest.starts_with('\n') => Ok(at + trailing + 1),
+            _ if rest.starts_with("\r\n") => Ok(at + trailing + 2),
+            _ => Err(self.diagnostic(
+                "expected a line break after -->".to_string(),
+                self.char_at(at + trailing),
+            )),
+        }
+    }
+}
+
+/// Whether the `<!--embed` at `start` begins a tag rather than a comment such as `<!--embed-meta`
+fn is_tag(source: &str, start: usize) -> bool {
+    let rest = &source[start + TAG_START.len()..];
+    match rest.chars().next() {
+        Some('-') => rest.starts_with(TAG_END),
+        Some(c) => !(c.is_alphanumeric() || c == '_'),
+        None => true,
+    }
+}
+
+/// Splits markdown into text and the tokens of each tag. A tag that can't be read is kept as text
+/// and reported as a diagnostic, tags in fenced code blocks are kept as text too.
+pub fn tokenize(source: &str) -> (Vec<Token>, Vec<Diagnostic>) {
+    let lexer = Lexer::new(source);
+    let mut tokens = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut text_start = 0;
+    let mut search = 0;
+    while let Some(found) = source[search..].find(TAG_START) {
+        let start = search + found;
+        search = start + TAG_START.len();
+        if let Some(end) = lexer.fence_end(start) {
+            search = end;
+            continue;
+        }
+        if !is_tag(source, start) {
+            continue;
+        }
+        match lexer.tag(start) {
+            Ok((tag, end)) => {
+                if text_start < start {
+                    tokens.push(lexer.token(TokenKind::Text, text_start..start));
+                }
+                tokens.extend(tag);
+                text_start = end;
+                search = end;
+            }
+            Err(diagnostic) => {
+                diagnostics.push(diagnostic);
+                // Carry on after the comment so one bad tag doesn't hide the rest
+                search = source[search..]
+                    .find(TAG_END)
+                    .map_or(source.len(), |i| search + i + TAG_END.len());
+            }
+        }
+    }
+    if text_start < source.len() {
+        tokens.push(lexer.token(TokenKind::Text, text_start..source.len()));
+    }
+    (tokens, diagnostics)
+}
+
+fn attribute(token: Token) -> Option<Attribute> {
+    match token.kind {
+        TokenKind::Attribute { key, value } => Some(Attribute {
+            key,
+            value,
+            span: token.span,
+        }),
+        _ => None,
+    }
+}
+
+/// Finds every tag in markdown, along with diagnostics for the `<!--embed` comments that aren't
+/// valid tags, ordered by where they are in the source
+pub fn parse_tags(source: &str) -> (Vec<Tag>, Vec<Diagnostic>) {
+    let (tokens, mut diagnostics) = tokenize(source);
+    let lines = Lines::new(source);
+    let mut tags = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(open) = tokens.next() {
+        if open.kind != TokenKind::Open {
+            continue;
+        }
+        let mut function = String::new();
+        let mut attributes: Vec<Attribute> = Vec::new();
+        let mut is_end = false;
+        let mut end = open.span.end;
+        for token in tokens.by_ref() {
+            match token.kind {
+                TokenKind::Function(name) => function = name,
+                TokenKind::Attribute { .. } => attributes.extend(attribute(token)),
+                TokenKind::EndMarker => is_end = true,
+                TokenKind::Close => {
+                    end = token.span.end;
+                    break;
+                }
+                TokenKind::Text | TokenKind::Open => break,
+            }
+        }
+        let span = lines.span(open.span.start..end);
+        let duplicate = attributes
+            .iter()
+            .enumerate()
+            .find(|(i, a)| attributes[..*i].iter().any(|b| b.key == a.key));
+        if let Some((_, attribute)) = duplicate {
+            diagnostics.push(Diagnostic {
+                message: format!("'{}' is set more than once", attribute.key),
+                span: attribute.span,
+            });
+            continue;
+        }
+        let Some(id) = attributes.iter().position(|a| a.key == "id") else {
+            diagnostics.push(Diagnostic {
+                message: format!("the {} tag has no id", function),
+                span,
+            });
+            continue;
+        };
+        let id = attributes.remove(id).value;
+        tags.push(Tag {
+            function,
+            id,
+            attributes,
+            is_end,
+            span,
+        });
+    }
+    diagnostics.sort_by_key(|d| d.span.start);
+    (tags, diagnostics)
+}
+
+/// Finds every `<!--embed-<name> ... -->` directive, along with diagnostics for those whose
+/// attributes can't be read
+pub fn parse_directives(source: &str, name: &str) -> (Vec<Directive>, Vec<Diagnostic>) {
+    let lexer = Lexer::new(source);
+    let opening = format!("{}-{}", TAG_START, name);
+    let mut directives = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut search = 0;
+    while let Some(found) = source[search..].find(&opening) {
+        let start = search + found;
+        let open = start..start + opening.len();
+        search = open.end;
+        if let Some(end) = lexer.fence_end(start) {
+            search = end;
+            continue;
+        }
+        let rest = &source[open.end..];
+        if !(rest.starts_with(char::is_whitespace) || rest.starts_with(TAG_END)) {
+            continue;
+        }
+        let attributes = lexer
+            .attributes(open.end, open.clone())
+            .and_then(|(tokens, at)| match source[at..].starts_with(TAG_END) {
+                true => Ok((tokens, at + TAG_END.len())),
+                false => {
+                    Err(lexer.diagnostic(format!("unexpected '/' in {}", opening), at..at + 1))
+                }
+            });
+        match attributes {
+            Ok((tokens, end)) => {
+                directives.push(Directive {
+                    attributes: tokens.into_iter().filter_map(attribute).collect(),
+                    span: lexer.lines.span(start..end),
+                });
+                search = end;
+            }
+            Err(diagnostic) => {
+                diagnostics.push(diagnostic);
+                search = source[search..]
+                    .find(TAG_END)
+                    .map_or(source.len(), |i| search + i + TAG_END.len());
+            }
+        }
+    }
+    (directives, diagnostics)
+}
+
+/// Finds every `<!--embed-<name> ... -->` block, along with diagnostics for those never closed.
+/// Like directives, blocks in fenced code blocks are examples and are left out.
+pub fn parse_blocks(source: &str, name: &str) -> (Vec<Block>, Vec<Diagnostic>) {
+    let lexer = Lexer::new(source);
+    let opening = format!("{}-{}", TAG_START, name);
+    let mut blocks = Vec::new();
+    let mut diagnostics = Vec::new();
+    let mut search = 0;
+    while let Some(found) = source[search..].find(&opening) {
+        let start = search + found;
+        let open = start..start + opening.len();
+        search = open.end;
+        if let Some(end) = lexer.fence_end(start) {
+            search = end;
+            continue;
+        }
+        let rest = &source[open.end..];
+        if !(rest.starts_with(char::is_whitespace) || rest.starts_with(TAG_END)) {
+            continue;
+        }
+        let Some(close) = rest.find(TAG_END).map(|i| open.end + i) else {
+            diagnostics
+                .push(lexer.diagnostic(format!("{} is never closed with -->", opening), open));
+            break;
+        };
+        let end = close + TAG_END.len();
+        blocks.push(Block {
+            body: source[open.end..close].to_string(),
+            span: lexer.lines.span(start..end),
+        });
+        search = end;
+    }
+    (blocks, diagnostics)
+}
+
+/// Quotes a value so it reads back as the same value
+pub fn quote_value(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn diagnostic(source: &str) -> Diagnostic {
+        let (tags, diagnostics) = parse_tags(source);
+        assert!(tags.is_empty(), "{:?}", tags);
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        diagnostics[0].clone()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let source = "intro\n<!--embed exec-code id=\"a\" cache=\"hash\": -->\ntext\n";
+        let (tokens, diagnostics) = tokenize(source);
+        assert!(diagnostics.is_empty());
+        let kinds: Vec<TokenKind> = tokens.iter().map(|t| t.kind.clone()).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Text,
+                TokenKind::Open,
+                TokenKind::Function("exec-code".to_string()),
+                TokenKind::Attribute {
+                    key: "id".to_string(),
+                    value: "a".to_string()
+                },
+                TokenKind::Attribute {
+                    key: "cache".to_string(),
+                    value: "hash".to_string()
+                },
+                TokenKind::Close,
+                TokenKind::Text,
+            ]
+        );
+        let cache = &tokens[4].span;
+        assert_eq!(&source[cache.range()], "cache=\"hash\"");
+        assert_eq!((cache.line, cache.column), (2, 28));
+        assert_eq!(&source[tokens[5].span.range()], "-->\n");
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let source =
+            "<!--embed exec-code cache=\"hash\" id=\"a\" -->\n\n<!--embed exec-code id=\"a\"/-->";
+        let (tags, diagnostics) = parse_tags(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].id, "a");
+        assert_eq!(tags[0].function, "exec-code");
+        assert_eq!(tags[0].attributes[0].key, "cache");
+        assert!(!tags[0].is_end);
+        assert_eq!(tags[0].span.range(), 0..44);
+        assert!(tags[1].is_end);
+        assert_eq!(tags[1].span.line, 3);
+        assert_eq!(tags[1].span.end, source.len());
+    }
+
+    #[test]
+    fn test_other_comments_are_text() {
+        let source =
+            "<!--embed-meta hash=\"x\" -->\n<!--embedded -->\n<!--embed-defaults a=\"b\" -->\n";
+        let (tokens, diagnostics) = tokenize(source);
+        assert!(diagnostics.is_empty());
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].kind, TokenKind::Text);
+    }
+
+    #[test]
+    fn test_malformed_tags() {
+        let cases = [
+            ("<!--embed -->\n", "expected a function name", (1, 11)),
+            (
+                "a\n<!--embed exec-code id=\"a\" -->b\n",
+                "line break",
+                (2, 31),
+            ),
+            (
+                "<!--embed exec-code id=a -->\n",
+                "the value of 'id' should be quoted",
+                (1, 21),
+            ),
+            (
+                "<!--embed exec-code id=\"a\n",
+                "never closed with \"",
+                (1, 21),
+            ),
+            (
+                "<!--embed exec-code id='a\\' -->\n",
+                "never closed with '",
+                (1, 21),
+            ),
+            (
+                "<!--embed exec-code id=\"a\"",
+                "never closed with -->",
+                (1, 1),
+            ),
+            (
+                "<!--embed exec-code id=\"a\"cache=\"b\" -->\n",
+                "whitespace",
+                (1, 27),
+            ),
+            (
+                "<!--embed exec-code id=\"a\" o-lang=\"text\" -->\n",
+                "unexpected '-' after 'o'",
+                (1, 28),
+            ),
+            ("<!--embed exec-code {} -->\n", "unexpected '{'", (1, 21)),
+            ("<!--embed exec-code cache=\"a\" -->\n", "no id", (1, 1)),
+            (
+                "<!--embed exec-code id=\"a\" id=\"b\" -->\n",
+                "'id' is set more than once",
+                (1, 28),
+            ),
+        ];
+        for (source, message, (line, column)) in cases {
+            let diagnostic = diagnostic(source);
+            assert!(
+                diagnostic.message.contains(message),
+                "{}: {}",
+                source,
+                diagnostic.message
+            );
+            assert_eq!(
+                (diagnostic.span.line, diagnostic.span.column),
+                (line, column)
+            );
+        }
+    }
+
+    #[test]
+    fn test_attribute_grammar() {
+        let source = r#"<!--embed exec-code id="a" exec_path="/tmp/a:b": env='A=1,B="2"' fail_on_error quote="say \"hi\"\n" path="C:\dir" -->
+"#;
+        let (tags, diagnostics) = parse_tags(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        let attributes: Vec<(&str, &str)> = tags[0]
+            .attributes
+            .iter()
+            .map(|a| (a.key.as_str(), a.value.as_str()))
+            .collect();
+        assert_eq!(
+            attributes,
+            vec![
+                ("exec_path", "/tmp/a:b"),
+                ("env", "A=1,B=\"2\""),
+                ("fail_on_error", "true"),
+                ("quote", "say \"hi\"\n"),
+                ("path", "C:\\dir"),
+            ]
+        );
+        assert_eq!(
+            &source[tags[0].attributes[1].span.range()],
+            "env='A=1,B=\"2\"'"
+        );
+    }
+
+    #[test]
+    fn test_parse_directives() {
+        let source = "<!--embed-defaults env=\"A=1,B=2\": cache=\"hash\" -->\n<!--embed-defaultsx a=\"b\" -->\n<!--embed-defaults -->";
+        let (directives, diagnostics) = parse_directives(source, "defaults");
+        assert!(diagnostics.is_empty());
+        assert_eq!(directives.len(), 2);
+        assert_eq!(directives[0].attributes[0].value, "A=1,B=2");
+        assert_eq!(directives[0].attributes[1].value, "hash");
+        assert!(directives[1].attributes.is_empty());
+        assert_eq!(directives[1].span.line, 3);
+
+        let (_, diagnostics) = parse_directives(
+            "<!--embed-meta hash=\"a\" last_run -->\n<!--embed-meta hash=\"x -->",
+            "meta",
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            (diagnostics[0].span.line, diagnostics[0].span.column),
+            (2, 16)
+        );
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        let source = "```markdown\n<!--embed-config env\nA=example\n-->\n```\n<!--embed-config env\nA=1\n-->\n<!--embed-config envx -->\n<!--embed-config env\nC=3";
+        let (blocks, diagnostics) = parse_blocks(source, "config env");
+        assert_eq!(blocks.len(), 1, "the fenced example is left out");
+        assert_eq!(blocks[0].body, "\nA=1\n");
+        assert_eq!(blocks[0].span.line, 6);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].span.line, 10);
+    }
+
+    #[test]
+    fn test_fenced_code_is_text() {
+        let source = "````markdown\n<!--embed identity id=\"example\" -->\n```\n<!--embed\n````\n<!--embed identity id=\"a\" -->\n~~~\n<!--embed-defaults x=1 -->\n";
+        let (tags, diagnostics) = parse_tags(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].id, "a");
+        assert!(parse_directives(source, "defaults").1.is_empty());
+    }
+
+    #[test]
+    fn test_recovers_after_malformed_tag() {
+        let source = "<!--embed exec-code id=a -->\n<!--embed identity id=\"b\" -->\n";
+        let (tags, diagnostics) = parse_tags(source);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].id, "b");
+    }
+
+    #[test]
+    fn test_columns_count_characters() {
+        let source = "é <!--embed identity -->\n";
+        let diagnostic = diagnostic(source);
+        assert_eq!((diagnostic.span.line, diagnostic.span.column), (1, 3));
+        assert_eq!(diagnostic.span.location(), Location::from_byte(source, 3));
+    }
+
+    /// Markdown built from fragments likely to confuse the lexer
+    fn fragments() -> impl Strategy<Value = String> {
+        let fragment = prop_oneof![
+            Just("<!--embed".to_string()),
+            Just(" exec-code".to_string()),
+            Just(" id=\"a\"".to_string()),
+            Just(" x=".to_string()),
+            Just("\"".to_string()),
+            Just(":".to_string()),
+            Just("/-->".to_string()),
+            Just("-->".to_string()),
+            Just("\n".to_string()),
+            Just("\r\n".to_string()),
+            Just("<!--embed-meta".to_string()),
+            "\\PC{0,4}",
+        ];
+        prop::collection::vec(fragment, 0..24).prop_map(|parts| parts.concat())
+    }
+
+    fn check_spans(source: &str, span: &Span) {
+        assert!(span.start <= span.end && span.end <= source.len());
+        assert!(source.is_char_boundary(span.start) && source.is_char_boundary(span.end));
+        assert_eq!(span.location(), Location::from_byte(source, span.start));
+    }
+
+    proptest! {
+        #[test]
+        fn fuzz_tokenize(source in fragments()) {
+            let (tokens, diagnostics) = tokenize(&source);
+            // Text and tags follow on from each other, covering the whole source
+            let mut end = 0;
+            for token in &tokens {
+                check_spans(&source, &token.span);
+                match token.kind {
+                    TokenKind::Text | TokenKind::Open => prop_assert_eq!(token.span.start, end),
+                    _ => prop_assert!(token.span.start >= end),
+                }
+                end = token.span.end;
+            }
+            prop_assert_eq!(end, source.len());
+            for diagnostic in &diagnostics {
+                check_spans(&source, &diagnostic.span);
+            }
+        }
+
+        #[test]
+        fn fuzz_quote_value_round_trip(value in "\\PC*") {
+            let source = format!("<!--embed-meta value={} -->", quote_value(&value));
+            let (directives, diagnostics) = parse_directives(&source, "meta");
+            prop_assert!(diagnostics.is_empty());
+            prop_assert_eq!(&directives[0].attributes[0].value, &value);
+        }
+
+        #[test]
+        fn fuzz_parse_tags_round_trip(
+            id in "\\PC*",
+            key in "[a-z_]{1,8}",
+            value in "\\PC*",
+            prefix in "[^<]*",
+        ) {
+            prop_assume!(key != "id");
+            let source = format!(
+                "{}\n<!--embed exec-code id={} {}={} /-->\n",
+                prefix,
+                quote_value(&id),
+                key,
+                quote_value(&value)
+            );
+            let (tags, diagnostics) = parse_tags(&source);
+            prop_assert!(diagnostics.is_empty());
+            prop_assert_eq!(tags.len(), 1);
+            prop_assert_eq!(&tags[0].id, &id);
+            prop_assert_eq!(&tags[0].attributes[0].value, &value);
+            prop_assert!(tags[0].is_end);
+            prop_assert_eq!(tags[0].span.end, source.len());
+        }
+    }
+}
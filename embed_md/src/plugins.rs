@@ -0,0 +1,392 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use embed_md_derive::RangeFn;
+use embed_md_traits::{format_duration, optional_param, Context, EmbedError, FunctionTag, RunMode};
+
+use crate::chunks::{ensure_run_dir, meta_line, read_meta, run_dir};
+use crate::config::Config;
+use crate::functions::FunctionArgs;
+use crate::languages::{execute, RunSettings};
+use crate::parser::parse_directives;
+
+/// The version of the JSON spoken with plugins, sent in every request and expected in every
+/// response
+pub const PLUGIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Plugins on `PATH` are named this followed by the function they run, such as `embed_md-toc`
+pub const PLUGIN_PREFIX: &str = "embed_md-";
+
+/// What a plugin is sent on stdin, as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginRequest {
+    pub version: u32,
+    /// The function the tag names, so one program can serve several
+    pub function: String,
+    pub id: String,
+    /// The markdown file the tag is in, none for content without one such as stdin
+    pub file: Option<PathBuf>,
+    /// The tag's params layered over the defaults
+    pub params: BTreeMap<String, String>,
+    /// The text between the tags, without its `embed-meta`
+    pub text: String,
+    /// The `embed-meta` the plugin responded with last time
+    pub meta: BTreeMap<String, String>,
+    /// `write`, `check` when the result is compared with the file instead of written, or `dry-run`
+    /// when it is diffed against the file. Neither of the last two should change anything on disk.
+    pub mode: String,
+    /// The run was asked to ignore caches
+    pub force: bool,
+}
+
+/// What a plugin writes to stdout, as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PluginResponse {
+    pub version: u32,
+    /// Replaces the text between the tags
+    pub text: String,
+    /// Written to an `embed-meta` line before the text and sent back in the next request. Keys
+    /// are letters, digits and `_`
+    #[serde(default)]
+    pub meta: BTreeMap<String, String>,
+}
+
+/// A function run by an external program, which reads a [`PluginRequest`] and writes a
+/// [`PluginResponse`]. It runs from the same directory, with the same environment and timeout,
+/// as `exec-code` would.
+#[derive(RangeFn, Debug, Clone, PartialEq)]
+pub struct Plugin {
+    id: String,
+    program: PathBuf,
+    function: String,
+    params: HashMap<String, String>,
+    is_end: bool,
+    range: Range<usize>,
+}
+
+impl Plugin {
+    pub fn new(program: PathBuf, function: String, args: FunctionArgs) -> Self {
+        Plugin {
+            id: args.id,
+            program,
+            function,
+            params: args.params,
+            is_end: args.is_end,
+            range: args.range,
+        }
+    }
+
+    fn request(&self, text: &str, context: &Context) -> PluginRequest {
+        let (meta, text) = split_meta(text);
+        PluginRequest {
+            version: PLUGIN_PROTOCOL_VERSION,
+            function: self.function.clone(),
+            id: self.id.clone(),
+            file: context
+                .source
+                .as_ref()
+                .map(|file| fs::canonicalize(file).unwrap_or(file.clone())),
+            params: self.params.clone().into_iter().collect(),
+            text: text.to_string(),
+            meta,
+            mode: match context.mode {
+                RunMode::Write => "write",
+                RunMode::Check => "check",
+                RunMode::DryRun => "dry-run",
+            }
+            .to_string(),
+            force: context.force,
+        }
+    }
+
+    fn run(&self, text: &str, context: &Context) -> Result<String, EmbedError> {
+        let name = self.program.to_string_lossy();
+        let fail = |reason: String| EmbedError::exec(format!("plugin {} {}", name, reason));
+        let request = serde_json::to_string(&self.request(text, context))
+            .map_err(|e| fail(format!("request couldn't be written: {}", e)))?;
+        // Plugins take any params, only those about how it runs are read here
+        let path = |key: &str| optional_param::<PathBuf>(&self.params, key, None);
+        let timeout = optional_param::<Duration>(&self.params, "timeout", None)?;
+        let (exec_path, r_exec_path) = (path("exec_path")?, path("r_exec_path")?);
+        let dir = run_dir(exec_path.as_deref(), r_exec_path.as_deref(), context);
+        ensure_run_dir(&dir, exec_path.as_deref(), r_exec_path.as_deref())?;
+        let settings = RunSettings {
+            dir,
+            capture_stderr: true,
+            timeout: timeout.filter(|t| !t.is_zero()),
+            env: context.env.clone(),
+        };
+        let mut command = settings.command(&name, &[]);
+        command.stdin(Stdio::piped());
+        let execution = execute(command, &request, settings.deadline())?;
+        let output = execution.output;
+        if execution.timed_out {
+            return Err(fail(format!(
+                "timed out after {}",
+                settings.timeout.map(format_duration).unwrap_or_default()
+            )));
+        }
+        if !output.status.success() {
+            return Err(fail(format!(
+                "exited with status {}: {}",
+                output
+                    .status
+                    .code()
+                    .map_or("signal".to_string(), |c| c.to_string()),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        std::io::stderr().write_all(&output.stderr)?;
+        let response: PluginResponse = serde_json::from_slice(&output.stdout)
+            .map_err(|e| fail(format!("wrote an invalid response: {}", e)))?;
+        if response.version != PLUGIN_PROTOCOL_VERSION {
+            return Err(fail(format!(
+                "speaks protocol version {}, expected {}",
+                response.version, PLUGIN_PROTOCOL_VERSION
+            )));
+        }
+        if let Some(key) = response
+            .meta
+            .keys()
+            .find(|key| key.is_empty() || !key.chars().all(|c| c.is_alphanumeric() || c == '_'))
+        {
+            return Err(fail(format!("wrote an invalid meta key '{}'", key)));
+        }
+        let meta = match response.meta.is_empty() {
+            true => String::new(),
+            false => meta_line(
+                &response
+                    .meta
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.as_str()))
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        Ok(meta + &response.text)
+    }
+}
+
+impl FunctionTag for Plugin {
+    fn transform(&self, text: String, context: &Context) -> Result<String, EmbedError> {
+        self.run(&text, context).map_err(|e| e.with_id(&self.id))
+    }
+}
+
+/// Separates the `embed-meta` line at the start of an embed from the rest of its text
+fn split_meta(text: &str) -> (BTreeMap<String, String>, &str) {
+    match parse_directives(text, "meta").0.first() {
+        Some(directive) if directive.span.start == 0 => {
+            let rest = &text[directive.span.end..];
+            (
+                read_meta(text).into_iter().collect(),
+                rest.strip_prefix('\n').unwrap_or(rest),
+            )
+        }
+        _ => (BTreeMap::new(), text),
+    }
+}
+
+/// The program to run for a function that isn't registered: the one the project config gives
+/// it, otherwise `embed_md-<function>` on `PATH`
+pub fn find_plugin(function: &str, config: &Config) -> Option<PathBuf> {
+    if let Some(program) = config.plugins.get(function) {
+        return Some(PathBuf::from(shellexpand::tilde(program).to_string()));
+    }
+    // Anything else could reach outside of `PATH`
+    if !function
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+    search_path(
+        &format!("{}{}", PLUGIN_PREFIX, function),
+        &env::var_os("PATH")?,
+    )
+}
+
+/// The first executable named `program` in a `PATH` like list of directories
+fn search_path(program: &str, path: &OsStr) -> Option<PathBuf> {
+    env::split_paths(path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunks::tests::context;
+    #[cfg(unix)]
+    use crate::config::CONFIG_FILE;
+    #[cfg(unix)]
+    use crate::{render, Options};
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    /// The plugin in the samples, written the way a team without Rust might
+    #[cfg(unix)]
+    const REFERENCE_PLUGIN: &str = "../samples/plugins/embed_md-upper";
+
+    #[cfg(unix)]
+    fn script(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn plugin(program: PathBuf, params: &[(&str, &str)]) -> Plugin {
+        Plugin::new(
+            program,
+            "upper".to_string(),
+            FunctionArgs {
+                id: "a".to_string(),
+                params: params
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+                is_end: false,
+                range: 0..0,
+            },
+        )
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_search_path() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let empty = dir.join("empty");
+        fs::create_dir_all(&empty).unwrap();
+        script(dir, "embed_md-toc", "#!/bin/sh\n");
+        fs::write(dir.join("embed_md-data"), "").unwrap();
+        let path = env::join_paths([empty.as_path(), dir]).unwrap();
+        assert_eq!(
+            search_path("embed_md-toc", &path),
+            Some(dir.join("embed_md-toc"))
+        );
+        // Not executable
+        assert_eq!(search_path("embed_md-data", &path), None);
+        assert_eq!(search_path("embed_md-missing", &path), None);
+    }
+
+    #[test]
+    fn test_find_plugin() {
+        let mut config = Config::default();
+        config
+            .plugins
+            .insert("toc".to_string(), "/opt/toc.py".to_string());
+        assert_eq!(
+            find_plugin("toc", &config),
+            Some(PathBuf::from("/opt/toc.py"))
+        );
+        assert_eq!(find_plugin("../../bin/sh", &config), None);
+    }
+
+    #[test]
+    fn test_request() {
+        let plugin = plugin(PathBuf::from("unused"), &[("cache", "hash")]);
+        let request = plugin.request(
+            "<!--embed-meta lines=\"1\" -->\nhello\n",
+            &context(RunMode::Check),
+        );
+        assert_eq!(request.version, PLUGIN_PROTOCOL_VERSION);
+        assert_eq!(request.function, "upper");
+        assert_eq!(request.file, Some(fs::canonicalize("Cargo.toml").unwrap()));
+        assert_eq!(
+            request.params,
+            BTreeMap::from([("cache".to_string(), "hash".to_string())])
+        );
+        assert_eq!(request.text, "hello\n");
+        assert_eq!(
+            request.meta,
+            BTreeMap::from([("lines".to_string(), "1".to_string())])
+        );
+        assert_eq!(request.mode, "check");
+        assert!(!request.force);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_reference_plugin() {
+        let plugin = plugin(fs::canonicalize(REFERENCE_PLUGIN).unwrap(), &[]);
+        let context = context(RunMode::Write);
+        let first = plugin
+            .transform("hello\nworld\n".to_string(), &context)
+            .unwrap();
+        assert_eq!(first, "<!--embed-meta lines=\"2\" -->\nHELLO\nWORLD\n");
+        // The meta isn't part of the text the next run sees
+        assert_eq!(plugin.transform(first.clone(), &context).unwrap(), first);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_render_with_plugin() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(
+            dir.join(CONFIG_FILE),
+            format!(
+                "[plugins]\nupper = {:?}\n",
+                fs::canonicalize(REFERENCE_PLUGIN).unwrap()
+            ),
+        )
+        .unwrap();
+        let content = "<!--embed upper id=\"a\" -->\nshout\n<!--embed upper id=\"a\" /-->\n";
+        let rendered = render(content, Some(&dir.join("doc.md")), &Options::default()).unwrap();
+        assert_eq!(
+            rendered,
+            "<!--embed upper id=\"a\" -->\n<!--embed-meta lines=\"1\" -->\nSHOUT\n<!--embed upper id=\"a\" /-->\n"
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_plugin_failures() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let cases = [
+            (
+                "#!/bin/sh\necho broken >&2\nexit 3\n",
+                "exited with status 3: broken",
+            ),
+            ("#!/bin/sh\necho nope\n", "wrote an invalid response"),
+            (
+                "#!/bin/sh\necho '{\"version\": 2, \"text\": \"\"}'\n",
+                "speaks protocol version 2, expected 1",
+            ),
+            (
+                "#!/bin/sh\necho '{\"version\": 1, \"text\": \"\", \"meta\": {\"a b\": \"\"}}'\n",
+                "wrote an invalid meta key 'a b'",
+            ),
+        ];
+        for (index, (content, expected)) in cases.into_iter().enumerate() {
+            let program = script(dir, &format!("plugin_{}", index), content);
+            let error = plugin(program, &[])
+                .transform(String::new(), &context(RunMode::Write))
+                .unwrap_err();
+            assert!(error.to_string().contains(expected), "{}", error);
+            assert_eq!(error.id.as_deref(), Some("a"));
+        }
+    }
+}
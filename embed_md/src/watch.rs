@@ -0,0 +1,527 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use embed_md_traits::EmbedError;
+
+use crate::config::Config;
+use crate::files::{collect_files, write_atomic};
+use crate::jobs::Jobs;
+use crate::{process_to_chunks, render_with, Options, RunMode};
+
+/// How long to wait for more changes once one arrives, so a save that touches a file several
+/// times is handled once
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// What watching did in response to a change
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// The embeds with these ids were run and the file rewritten
+    Rendered {
+        file: PathBuf,
+        ids: Vec<String>,
+    },
+    Failed(EmbedError),
+}
+
+/// An embed as it was last seen, enough to tell whether a change affects it
+#[derive(Debug, Clone, PartialEq)]
+struct Embed {
+    id: String,
+    /// The opening tag and everything up to the closing tag
+    source: String,
+    /// Ids of the embeds whose output it reads through `$$id$$`
+    references: Vec<String>,
+    depends_on: Vec<glob::Pattern>,
+}
+
+/// A markdown file as it was last read or written
+#[derive(Debug)]
+struct WatchedFile {
+    /// The path as it was found, relative paths stay relative so errors read the same as a run
+    path: PathBuf,
+    content: String,
+    embeds: Vec<Embed>,
+}
+
+fn embeds(content: &str, file: &Path, options: &Options) -> Result<Vec<Embed>, EmbedError> {
+    let config = Config::for_path(file)?;
+    let chunks = process_to_chunks(content, Some(file), RunMode::Write, options, &config)
+        .map_err(|e| e.with_file(file))?;
+    Ok(chunks
+        .iter()
+        // Identity chunks have no tags
+        .filter(|chunk| !chunk.opening_tag.is_empty())
+        .map(|chunk| Embed {
+            id: chunk.pair.start.id(),
+            source: format!("{}{}", chunk.opening_tag, chunk.text),
+            references: chunk.pair.start.dependencies(&chunk.text),
+            depends_on: chunk
+                .pair
+                .start
+                .depends_on(&chunk.context)
+                .iter()
+                .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+                .collect(),
+        })
+        .collect())
+}
+
+/// Ids of the embeds that are new or whose tag or text changed
+fn changed_ids(old: &[Embed], new: &[Embed]) -> Vec<String> {
+    new.iter()
+        .filter(|embed| {
+            !old.iter()
+                .any(|o| o.id == embed.id && o.source == embed.source)
+        })
+        .map(|embed| embed.id.clone())
+        .collect()
+}
+
+/// The ids along with every embed that reads one of them, directly or not, in document order
+fn with_dependents(embeds: &[Embed], ids: &[String]) -> Vec<String> {
+    let mut affected: Vec<&str> = ids.iter().map(String::as_str).collect();
+    loop {
+        let before = affected.len();
+        for embed in embeds {
+            if !affected.contains(&embed.id.as_str())
+                && embed
+                    .references
+                    .iter()
+                    .any(|r| affected.contains(&r.as_str()))
+            {
+                affected.push(&embed.id);
+            }
+        }
+        if affected.len() == before {
+            break;
+        }
+    }
+    embeds
+        .iter()
+        .filter(|embed| affected.contains(&embed.id.as_str()))
+        .map(|embed| embed.id.clone())
+        .collect()
+}
+
+/// The markdown files being watched, keyed by their canonical path as events report them
+struct Session<'a> {
+    paths: &'a [String],
+    options: &'a Options,
+    jobs: Jobs,
+    files: HashMap<PathBuf, WatchedFile>,
+}
+
+impl<'a> Session<'a> {
+    fn new(paths: &'a [String], options: &'a Options) -> (Self, Vec<WatchEvent>) {
+        let mut session = Session {
+            paths,
+            options,
+            jobs: Jobs::new(options.jobs),
+            files: HashMap::new(),
+        };
+        let events = session.refresh();
+        (session, events)
+    }
+
+    /// Starts tracking markdown files that aren't tracked yet, such as ones created since
+    fn refresh(&mut self) -> Vec<WatchEvent> {
+        let mut events = Vec::new();
+        for path in self.paths {
+            let found = Config::for_path(Path::new(path))
+                .and_then(|config| collect_files(path, &config.filter(&self.options.filter)));
+            let found = match found {
+                Ok(found) => found,
+                Err(e) => {
+                    events.push(WatchEvent::Failed(e));
+                    continue;
+                }
+            };
+            for file in found {
+                let Ok(canonical) = fs::canonicalize(&file) else {
+                    continue;
+                };
+                if self.files.contains_key(&canonical) {
+                    continue;
+                }
+                let content = fs::read_to_string(&file).unwrap_or_default();
+                let embeds = embeds(&content, &file, self.options).unwrap_or_else(|e| {
+                    events.push(WatchEvent::Failed(e));
+                    Vec::new()
+                });
+                self.files.insert(
+                    canonical,
+                    WatchedFile {
+                        path: file,
+                        content,
+                        embeds,
+                    },
+                );
+            }
+        }
+        events
+    }
+
+    /// Directories to watch so every markdown file and file they depend on is covered, and
+    /// whether their subdirectories need watching too. A single file is covered by its directory
+    /// alone, as editors often save by replacing the file rather than writing to it.
+    fn watched_dirs(&self) -> Vec<(PathBuf, RecursiveMode)> {
+        let mut dirs: BTreeMap<PathBuf, RecursiveMode> = BTreeMap::new();
+        let mut add = |dir: PathBuf, mode: RecursiveMode| {
+            let entry = dirs.entry(dir).or_insert(mode);
+            *entry = (*entry).min(mode);
+        };
+        for path in self
+            .paths
+            .iter()
+            .filter_map(|path| fs::canonicalize(path).ok())
+        {
+            match (path.is_dir(), path.parent()) {
+                (false, Some(parent)) => add(parent.to_path_buf(), RecursiveMode::NonRecursive),
+                _ => add(path, RecursiveMode::Recursive),
+            }
+        }
+        for file in self.files.values() {
+            for pattern in file.embeds.iter().flat_map(|e| &e.depends_on) {
+                if let Some((dir, mode)) = literal_dir(pattern) {
+                    add(dir, mode);
+                }
+            }
+        }
+        // Anything inside a directory watched recursively is already covered
+        let recursive: Vec<PathBuf> = dirs
+            .iter()
+            .filter(|(_, mode)| **mode == RecursiveMode::Recursive)
+            .map(|(dir, _)| dir.clone())
+            .collect();
+        dirs.into_iter()
+            .filter(|(dir, _)| !recursive.iter().any(|d| d != dir && dir.starts_with(d)))
+            .collect()
+    }
+
+    /// Runs the embeds affected by changes to `changed`, a change that leaves a markdown file as
+    /// it was last seen, such as the session's own write, is ignored
+    fn handle(&mut self, changed: &[PathBuf]) -> Vec<WatchEvent> {
+        let mut events = Vec::new();
+        if changed
+            .iter()
+            .any(|path| is_markdown(path) && !self.files.contains_key(path))
+        {
+            events.extend(self.refresh());
+        }
+        let mut affected: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        for path in changed {
+            if let Some(file) = self.files.get_mut(path) {
+                let content = match fs::read_to_string(path) {
+                    Ok(content) => content,
+                    Err(_) => {
+                        self.files.remove(path);
+                        continue;
+                    }
+                };
+                if content == file.content {
+                    continue;
+                }
+                let new_embeds = match embeds(&content, &file.path, self.options) {
+                    Ok(embeds) => embeds,
+                    Err(e) => {
+                        events.push(WatchEvent::Failed(e));
+                        file.content = content;
+                        file.embeds = Vec::new();
+                        continue;
+                    }
+                };
+                let ids = changed_ids(&file.embeds, &new_embeds);
+                file.content = content;
+                file.embeds = new_embeds;
+                affected.entry(path.clone()).or_default().extend(ids);
+            }
+            for (canonical, file) in &self.files {
+                let ids = file
+                    .embeds
+                    .iter()
+                    .filter(|embed| embed.depends_on.iter().any(|p| p.matches_path(path)))
+                    .map(|embed| embed.id.clone());
+                affected.entry(canonical.clone()).or_default().extend(ids);
+            }
+        }
+        for (canonical, ids) in affected {
+            if !ids.is_empty() {
+                events.extend(self.run(&canonical, &ids));
+            }
+        }
+        events
+    }
+
+    fn run(&mut self, canonical: &Path, ids: &[String]) -> Option<WatchEvent> {
+        let file = self.files.get_mut(canonical)?;
+        let mut ids = with_dependents(&file.embeds, ids);
+        // --id limits watching to those embeds too
+        ids.retain(|id| self.options.ids.is_empty() || self.options.ids.contains(id));
+        if ids.is_empty() {
+            return None;
+        }
+        let options = Options {
+            ids,
+            ..self.options.clone()
+        };
+        let result = render_with(&file.content, Some(&file.path), &options, &self.jobs).and_then(
+            |rendered| {
+                // Edited while the embeds ran, the edit is left alone and handled on its own
+                if fs::read_to_string(&file.path).ok().as_ref() != Some(&file.content) {
+                    return Ok(None);
+                }
+                if rendered != file.content {
+                    write_atomic(&file.path, &rendered, options.backup)?;
+                }
+                Ok(Some(rendered))
+            },
+        );
+        match result {
+            Ok(Some(rendered)) => {
+                file.embeds = embeds(&rendered, &file.path, self.options).unwrap_or_default();
+                file.content = rendered;
+                Some(WatchEvent::Rendered {
+                    file: file.path.clone(),
+                    ids: options.ids,
+                })
+            }
+            Ok(None) => None,
+            Err(e) => Some(WatchEvent::Failed(e)),
+        }
+    }
+}
+
+fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|x| crate::MARKDOWN_EXTENSIONS.iter().any(|ext| x == *ext))
+}
+
+/// The deepest existing directory of a glob before any wildcard, watched recursively unless the
+/// glob only matches files directly inside it
+fn literal_dir(pattern: &glob::Pattern) -> Option<(PathBuf, RecursiveMode)> {
+    let components = Path::new(pattern.as_str()).components().count();
+    let mut dir = PathBuf::new();
+    for component in Path::new(pattern.as_str()).components() {
+        match component {
+            Component::Normal(part) if part.to_string_lossy().contains(['*', '?', '[']) => break,
+            component => dir.push(component),
+        }
+    }
+    let dir = dir.ancestors().find(|ancestor| ancestor.is_dir())?;
+    let mode = match dir.components().count() + 1 == components && !pattern.as_str().contains("**")
+    {
+        true => RecursiveMode::NonRecursive,
+        false => RecursiveMode::Recursive,
+    };
+    Some((dir.to_path_buf(), mode))
+}
+
+/// Changes what the watcher watches to `wanted`, which can grow as embeds gain a `depends_on`.
+/// A directory that can't be watched is tried again next time.
+fn update_watches(
+    watcher: &mut impl Watcher,
+    watching: &mut Vec<(PathBuf, RecursiveMode)>,
+    wanted: Vec<(PathBuf, RecursiveMode)>,
+) -> Result<(), EmbedError> {
+    for (dir, _) in watching.iter().filter(|watched| !wanted.contains(watched)) {
+        // Gone already if the directory was removed
+        let _ = watcher.unwatch(dir);
+    }
+    watching.retain(|watched| wanted.contains(watched));
+    let mut result = Ok(());
+    for (dir, mode) in wanted {
+        if watching.contains(&(dir.clone(), mode)) {
+            continue;
+        }
+        match watcher.watch(&dir, mode) {
+            Ok(()) => watching.push((dir, mode)),
+            Err(e) => {
+                result = Err(EmbedError::from(std::io::Error::other(e)).with_file(&dir));
+            }
+        }
+    }
+    result
+}
+
+/// The paths an event changed, reads are left out
+fn changed_paths(event: notify::Result<Event>) -> Vec<PathBuf> {
+    match event {
+        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => event.paths,
+        _ => Vec::new(),
+    }
+}
+
+/// Watches the markdown files the paths resolve to, and every file they depend on, running the
+/// embeds a change affects. Runs until the watcher stops, reporting what it did as it goes.
+pub fn watch(
+    paths: &[String],
+    options: &Options,
+    mut report: impl FnMut(WatchEvent),
+) -> Result<(), EmbedError> {
+    let (mut session, events) = Session::new(paths, options);
+    events.into_iter().for_each(&mut report);
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(std::io::Error::other)?;
+    let mut watching = Vec::new();
+    update_watches(&mut watcher, &mut watching, session.watched_dirs())?;
+    while let Ok(event) = receiver.recv() {
+        let mut changed = changed_paths(event);
+        while let Ok(event) = receiver.recv_timeout(DEBOUNCE) {
+            changed.extend(changed_paths(event));
+        }
+        changed.sort();
+        changed.dedup();
+        session.handle(&changed).into_iter().for_each(&mut report);
+        // New files and edited embeds can depend on files outside what's watched
+        if let Err(e) = update_watches(&mut watcher, &mut watching, session.watched_dirs()) {
+            report(WatchEvent::Failed(e));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embed(id: &str, source: &str, references: &[&str]) -> Embed {
+        Embed {
+            id: id.to_string(),
+            source: source.to_string(),
+            references: references.iter().map(|r| r.to_string()).collect(),
+            depends_on: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_changed_ids_with_dependents() {
+        let old = vec![
+            embed("a", "one", &[]),
+            embed("b", "two", &["a"]),
+            embed("c", "three", &["b"]),
+            embed("d", "four", &[]),
+        ];
+        let mut new = old.clone();
+        new[0].source = "changed".to_string();
+        new.push(embed("e", "new", &[]));
+        let changed = changed_ids(&old, &new);
+        assert_eq!(changed, vec!["a", "e"]);
+        assert_eq!(with_dependents(&new, &changed), vec!["a", "b", "c", "e"]);
+    }
+
+    #[test]
+    fn test_session_handles_changes() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let doc = root.join("doc.md");
+        let data = root.join("data.txt");
+        fs::write(&data, "first\n").unwrap();
+        let out_dir = fs::canonicalize(root).unwrap();
+        let original = r#"<!--embed exec-code id="watch_writer" out_dir="OUT" -->
+```shell
+echo "writer"
+```
+<!--embed exec-code id="watch_writer" /-->
+<!--embed exec-code id="watch_reader" out_dir="OUT" -->
+```shell
+cat $$watch_writer$$
+```
+<!--embed exec-code id="watch_reader" /-->
+<!--embed exec-code id="watch_data" out_dir="OUT": depends_on="data.txt" -->
+```shell
+cat data.txt
+```
+<!--embed exec-code id="watch_data" /-->
+"#
+        .replace("OUT", &out_dir.to_string_lossy());
+        fs::write(&doc, &original).unwrap();
+        let paths = vec![root.to_string_lossy().to_string()];
+        let options = Options::default();
+        let (mut session, events) = Session::new(&paths, &options);
+        assert!(events.is_empty());
+        let canonical_root = fs::canonicalize(root).unwrap();
+        assert_eq!(
+            session.watched_dirs(),
+            vec![(canonical_root.clone(), RecursiveMode::Recursive)]
+        );
+        let doc = canonical_root.join("doc.md");
+        let data = canonical_root.join("data.txt");
+
+        fs::write(&doc, original.replace("\"writer\"", "\"edited\"")).unwrap();
+        let events = session.handle(std::slice::from_ref(&doc));
+        assert!(matches!(
+            &events[..],
+            [WatchEvent::Rendered { ids, .. }] if ids == &["watch_writer", "watch_reader"]
+        ));
+        let rendered = fs::read_to_string(&doc).unwrap();
+        assert_eq!(rendered.matches("```\nedited\n```\n").count(), 2);
+        assert!(!rendered.contains("first"));
+
+        // The session's own write isn't a change
+        assert!(session.handle(std::slice::from_ref(&doc)).is_empty());
+
+        fs::write(&data, "second\n").unwrap();
+        let events = session.handle(&[data]);
+        assert!(matches!(
+            &events[..],
+            [WatchEvent::Rendered { ids, .. }] if ids == &["watch_data"]
+        ));
+        assert!(fs::read_to_string(&doc)
+            .unwrap()
+            .contains("```\nsecond\n```\n"));
+
+        // Watching the file alone doesn't watch everything beside it
+        let paths = vec![doc.to_string_lossy().to_string()];
+        let (mut session, _) = Session::new(&paths, &options);
+        assert_eq!(
+            session.watched_dirs(),
+            vec![(canonical_root.clone(), RecursiveMode::NonRecursive)]
+        );
+        // A depends_on added since is watched from then on
+        fs::create_dir_all(root.join("inputs/nested")).unwrap();
+        let edited = fs::read_to_string(&doc)
+            .unwrap()
+            .replace("depends_on=\"data.txt\"", "depends_on=\"inputs/**/*.txt\"");
+        fs::write(&doc, edited).unwrap();
+        session.handle(std::slice::from_ref(&doc));
+        assert_eq!(
+            session.watched_dirs(),
+            vec![
+                (canonical_root.clone(), RecursiveMode::NonRecursive),
+                (canonical_root.join("inputs"), RecursiveMode::Recursive),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_literal_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("data")).unwrap();
+        let dir = |glob: &str| {
+            let pattern = glob::Pattern::new(&root.join(glob).to_string_lossy()).unwrap();
+            literal_dir(&pattern).unwrap()
+        };
+        assert_eq!(
+            dir("data.txt"),
+            (root.to_path_buf(), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            dir("data/*.json"),
+            (root.join("data"), RecursiveMode::NonRecursive)
+        );
+        assert_eq!(
+            dir("data/**/*.json"),
+            (root.join("data"), RecursiveMode::Recursive)
+        );
+        assert_eq!(
+            dir("missing/a.json"),
+            (root.to_path_buf(), RecursiveMode::Recursive)
+        );
+    }
+}
@@ -0,0 +1,29 @@
+// Everything comes from embed_md, the crate a function's author depends on
+use std::collections::HashMap;
+use std::time::Duration;
+
+use embed_md::{EmbedParams, ParamValue};
+
+#[derive(ParamValue, Debug, PartialEq)]
+enum Case {
+    Upper,
+    Lower,
+}
+
+#[derive(EmbedParams)]
+struct ShoutParams {
+    /// Added to every line
+    suffix: Option<String>,
+    #[param(default = "upper")]
+    case: Case,
+    timeout: Option<Duration>,
+}
+
+fn main() {
+    let params = HashMap::from([("suffix".to_string(), "!".to_string())]);
+    let parsed = ShoutParams::from_params(&params).unwrap();
+    assert_eq!(parsed.suffix.as_deref(), Some("!"));
+    assert_eq!(parsed.case, Case::Upper);
+    assert_eq!(parsed.timeout, None);
+    assert_eq!(ShoutParams::help()[0].doc, "Added to every line");
+}
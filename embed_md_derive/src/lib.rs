@@ -1,23 +1,331 @@
 use proc_macro::TokenStream;
-use quote::quote;
-// extern crate embed_md_traits;
-// use embed_md_traits::Rangeable;
-use syn::{parse_macro_input, DeriveInput};
+use quote::{quote, quote_spanned, ToTokens};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Expr, Field, Fields, GenericArgument, Lit,
+    LitStr, Member, PathArguments, Type,
+};
 
-#[proc_macro_derive(RangeFn)]
+/// Implements `Rangeable` from the struct's `range` and `id` fields, or the fields marked
+/// `#[range]` and `#[id]`
+#[proc_macro_derive(RangeFn, attributes(range, id))]
 pub fn derive_range_fn(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let name = input.ident;
-    let expanded = quote! {
-        impl Rangeable for #name {
-            fn range(&self) -> Range<usize> {
-                return self.range.clone()
+    match range_fn(&input) {
+        Ok(expanded) => TokenStream::from(expanded),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Implements `EmbedParams` for a struct with named fields, each a param named after the field.
+/// Fields are read with their type's `ParamValue`, an `Option` field may be left out and any
+/// other needs `#[param(default = "...")]` unless it must always be given. The doc comment of a
+/// field is its help.
+#[proc_macro_derive(EmbedParams, attributes(param))]
+pub fn derive_embed_params(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match embed_params(&input) {
+        Ok(expanded) => TokenStream::from(expanded),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Implements `ParamValue` for an enum of unit variants, each read from its name in kebab case
+/// so `DryRun` is `dry-run`
+#[proc_macro_derive(ParamValue)]
+pub fn derive_param_value(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match param_value(&input) {
+        Ok(expanded) => TokenStream::from(expanded),
+        Err(e) => TokenStream::from(e.to_compile_error()),
+    }
+}
+
+/// Where the code `EmbedParams` and `ParamValue` expand to finds the traits, re-exported by
+/// `embed_md` so crates using the derives only need to depend on it
+fn krate() -> proc_macro2::TokenStream {
+    quote!(::embed_md::__private)
+}
+
+fn embed_params(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let krate = krate();
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "EmbedParams needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "EmbedParams needs a struct with named fields",
+            ))
+        }
+    };
+    let mut keys = Vec::new();
+    let mut reads = Vec::new();
+    let mut help = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().expect("named fields have names");
+        let key = ident.to_string();
+        let key = key.strip_prefix("r#").unwrap_or(&key).to_string();
+        let default = match param_default(&field.attrs)? {
+            Some(default) => quote!(::std::option::Option::Some(#default)),
+            None => quote!(::std::option::Option::None),
+        };
+        let doc = doc(&field.attrs);
+        let (value, read) = match option_inner(&field.ty) {
+            Some(inner) => (inner, quote!(#krate::optional_param)),
+            None => (&field.ty, quote!(#krate::required_param)),
+        };
+        reads.push(quote! {
+            #ident: #read::<#value>(params, #key, #default)?
+        });
+        help.push(quote! {
+            #krate::ParamHelp {
+                name: #key,
+                kind: <#value as #krate::ParamValue>::KIND,
+                default: #default,
+                doc: #doc,
+            }
+        });
+        keys.push(key);
+    }
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::EmbedParams for #name #type_generics #where_clause {
+            fn from_params(
+                params: &::std::collections::HashMap<::std::string::String, ::std::string::String>,
+            ) -> ::std::result::Result<Self, #krate::EmbedError> {
+                #krate::reject_unknown_params(params, &[#(#keys),*])?;
+                ::std::result::Result::Ok(#name {
+                    #(#reads,)*
+                })
             }
 
-            fn id(&self) -> String {
-                return self.id.clone()
+            fn help() -> ::std::vec::Vec<#krate::ParamHelp> {
+                ::std::vec![#(#help),*]
             }
         }
+    })
+}
+
+fn range_fn(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        Data::Enum(data) => {
+            return Err(syn::Error::new(
+                data.enum_token.span,
+                "RangeFn can only be derived for a struct",
+            ))
+        }
+        Data::Union(data) => {
+            return Err(syn::Error::new(
+                data.union_token.span,
+                "RangeFn can only be derived for a struct",
+            ))
+        }
     };
-    TokenStream::from(expanded)
+    let range = marked_field(name, fields, "range")?;
+    let id = marked_field(name, fields, "id")?;
+    // Spanned to the fields, so a field of the wrong type is reported where it is declared
+    let range_body = quote_spanned! {range.ty.span()=>
+        ::core::clone::Clone::clone(&self.#range)
+    };
+    let id_body = quote_spanned! {id.ty.span()=>
+        ::core::clone::Clone::clone(&self.#id)
+    };
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::embed_md_traits::Rangeable for #name #type_generics #where_clause {
+            fn range(&self) -> ::core::ops::Range<usize> {
+                #range_body
+            }
+
+            fn id(&self) -> ::std::string::String {
+                #id_body
+            }
+        }
+    })
+}
+
+/// A field `RangeFn` reads, with how to refer to it
+struct MarkedField<'a> {
+    member: Member,
+    ty: &'a Type,
+}
+
+impl ToTokens for MarkedField<'_> {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.member.to_tokens(tokens)
+    }
+}
+
+/// The field marked `#[<marker>]`, otherwise the one named `<marker>`
+fn marked_field<'a>(
+    name: &syn::Ident,
+    fields: &'a Fields,
+    marker: &str,
+) -> syn::Result<MarkedField<'a>> {
+    let member = |(index, field): (usize, &'a Field)| MarkedField {
+        member: match &field.ident {
+            Some(ident) => Member::Named(ident.clone()),
+            None => Member::Unnamed(index.into()),
+        },
+        ty: &field.ty,
+    };
+    let mut marked = None;
+    for (index, field) in fields.iter().enumerate() {
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident(marker))
+        {
+            if attr.meta.require_path_only().is_err() {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    format!("#[{}] takes no arguments", marker),
+                ));
+            }
+            if marked.is_some() {
+                return Err(syn::Error::new_spanned(
+                    attr,
+                    format!("only one field can be marked #[{}]", marker),
+                ));
+            }
+            marked = Some(member((index, field)));
+        }
+    }
+    if let Some(marked) = marked {
+        return Ok(marked);
+    }
+    fields
+        .iter()
+        .enumerate()
+        .find(|(_, field)| field.ident.as_ref().is_some_and(|ident| ident == marker))
+        .map(member)
+        .ok_or_else(|| {
+            syn::Error::new_spanned(
+                name,
+                format!(
+                    "RangeFn needs a field named `{}` or one marked #[{}]",
+                    marker, marker
+                ),
+            )
+        })
+}
+
+fn param_value(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let krate = krate();
+    let name = &input.ident;
+    let variants = match &input.data {
+        Data::Enum(data) => &data.variants,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "ParamValue can only be derived for an enum",
+            ))
+        }
+    };
+    let mut values = Vec::new();
+    let mut arms = Vec::new();
+    for variant in variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(syn::Error::new_spanned(
+                variant,
+                "ParamValue variants can't have fields",
+            ));
+        }
+        let ident = &variant.ident;
+        let value = kebab_case(&ident.to_string());
+        arms.push(quote!(#value => ::std::result::Result::Ok(#name::#ident)));
+        values.push(value);
+    }
+    let kind = values.join("|");
+    let expected = match values.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    };
+    let message = format!("'{{}}' should be one of {}", expected);
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics #krate::ParamValue for #name #type_generics #where_clause {
+            const KIND: &'static str = #kind;
+
+            fn parse_param(
+                value: &str,
+            ) -> ::std::result::Result<Self, ::std::string::String> {
+                match value {
+                    #(#arms,)*
+                    _ => ::std::result::Result::Err(::std::format!(#message, value)),
+                }
+            }
+        }
+    })
+}
+
+/// The type inside `Option<T>`, none for any other type
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let Type::Path(path) = ty else { return None };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The value of `#[param(default = "...")]`
+fn param_default(attrs: &[Attribute]) -> syn::Result<Option<LitStr>> {
+    let mut default = None;
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("param")) {
+        attr.parse_nested_meta(|meta| match meta.path.is_ident("default") {
+            true => {
+                default = Some(meta.value()?.parse()?);
+                Ok(())
+            }
+            false => Err(meta.error("expected `default = \"...\"`")),
+        })?;
+    }
+    Ok(default)
+}
+
+/// The lines of a doc comment joined into one
+fn doc(attrs: &[Attribute]) -> String {
+    attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .filter_map(|attr| match &attr.meta.require_name_value().ok()?.value {
+            Expr::Lit(expr) => match &expr.lit {
+                Lit::Str(line) => Some(line.value().trim().to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn kebab_case(name: &str) -> String {
+    let mut kebab = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            kebab.push('-');
+        }
+        kebab.extend(c.to_lowercase());
+    }
+    kebab
 }
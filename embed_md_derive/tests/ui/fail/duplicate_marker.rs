@@ -0,0 +1,14 @@
+use std::ops::Range;
+
+use embed_md_derive::RangeFn;
+
+#[derive(RangeFn)]
+struct Tag {
+    id: String,
+    #[range]
+    open: Range<usize>,
+    #[range]
+    close: Range<usize>,
+}
+
+fn main() {}
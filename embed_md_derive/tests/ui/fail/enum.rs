@@ -0,0 +1,9 @@
+use embed_md_derive::RangeFn;
+
+#[derive(RangeFn)]
+enum Tag {
+    Open,
+    Close,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use std::ops::Range;
+
+use embed_md_derive::RangeFn;
+
+#[derive(RangeFn)]
+struct Tag {
+    #[id(name)]
+    name: String,
+    range: Range<usize>,
+}
+
+fn main() {}
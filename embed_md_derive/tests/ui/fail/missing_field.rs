@@ -0,0 +1,11 @@
+use std::ops::Range;
+
+use embed_md_derive::RangeFn;
+
+#[derive(RangeFn)]
+struct Tag {
+    name: String,
+    range: Range<usize>,
+}
+
+fn main() {}
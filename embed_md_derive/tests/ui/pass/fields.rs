@@ -0,0 +1,47 @@
+use std::fmt::Debug;
+use std::ops::Range;
+
+use embed_md_derive::RangeFn;
+use embed_md_traits::Rangeable;
+
+#[derive(RangeFn)]
+struct Marked {
+    #[id]
+    name: String,
+    #[range]
+    span: Range<usize>,
+    // Marked fields win over those named after the method
+    #[allow(dead_code)]
+    range: Range<usize>,
+}
+
+#[derive(RangeFn)]
+struct Generic<'a, T: Debug>
+where
+    T: Clone,
+{
+    id: String,
+    range: Range<usize>,
+    #[allow(dead_code)]
+    value: &'a T,
+}
+
+#[derive(RangeFn)]
+struct Tuple(#[range] Range<usize>, #[id] String);
+
+fn main() {
+    let marked = Marked {
+        name: "a".to_string(),
+        span: 1..2,
+        range: 3..4,
+    };
+    assert_eq!((marked.id(), marked.range()), ("a".to_string(), 1..2));
+    let generic = Generic {
+        id: "b".to_string(),
+        range: 5..6,
+        value: &7,
+    };
+    assert_eq!((generic.id(), generic.range()), ("b".to_string(), 5..6));
+    let tuple = Tuple(8..9, "c".to_string());
+    assert_eq!((tuple.id(), tuple.range()), ("c".to_string(), 8..9));
+}
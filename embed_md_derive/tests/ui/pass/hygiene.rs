@@ -0,0 +1,23 @@
+// Nothing is imported and the names the derive used to rely on mean something else here
+use embed_md_derive::RangeFn;
+
+#[allow(dead_code)]
+struct Range;
+#[allow(dead_code)]
+struct Rangeable;
+
+#[derive(RangeFn)]
+struct Tag {
+    id: String,
+    range: std::ops::Range<usize>,
+}
+
+fn main() {
+    use embed_md_traits::Rangeable as _;
+    let tag = Tag {
+        id: "a".to_string(),
+        range: 1..2,
+    };
+    assert_eq!(tag.id(), "a");
+    assert_eq!(tag.range(), 1..2);
+}
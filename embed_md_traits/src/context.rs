@@ -0,0 +1,51 @@
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// How a run treats the markdown it processes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RunMode {
+    /// Files are rewritten with the results
+    #[default]
+    Write,
+    /// Results are compared with the files, which aren't touched. Nothing should change on disk,
+    /// including timestamps that would make the comparison fail.
+    Check,
+    /// Results are diffed against the files, which aren't touched. Like a check nothing changes on
+    /// disk.
+    DryRun,
+}
+
+/// Where embeds keep their outputs, so an embed can read the output of another in the same
+/// document
+pub trait OutputStore: Debug + Send + Sync {
+    /// Where the output of the embed with `id` is kept, whether or not it has been written
+    fn path(&self, id: &str) -> PathBuf;
+}
+
+/// Everything about the run an embed's function is part of, apart from the params on its tag
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// The markdown file the embed is in, none for content without one such as stdin
+    pub source: Option<PathBuf>,
+    /// The directory the document is in and that its relative paths are resolved from, the
+    /// current directory for content without a file
+    pub root: PathBuf,
+    /// The project config the document resolved to, see [`Context::config`]
+    pub config: Arc<dyn Any + Send + Sync>,
+    pub mode: RunMode,
+    /// Run even when a cache says the result is current
+    pub force: bool,
+    /// Variables for anything the embed runs, from the document, the tag and the command line
+    pub env: BTreeMap<String, String>,
+    pub outputs: Arc<dyn OutputStore>,
+}
+
+impl Context {
+    /// The project config, as the type the embedding application stores it as
+    pub fn config<T: Any>(&self) -> Option<&T> {
+        self.config.downcast_ref()
+    }
+}
@@ -0,0 +1,178 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// Where in a markdown file an error was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub byte: usize,
+    pub line: usize,
+    /// Counted in characters rather than bytes
+    pub column: usize,
+}
+
+impl Location {
+    /// Builds a location from a byte offset into `text`, lines and columns are 1 indexed
+    pub fn from_byte(text: &str, byte: usize) -> Self {
+        let before = &text.as_bytes()[..byte.min(text.len())];
+        let line = before.iter().filter(|b| **b == b'\n').count() + 1;
+        let line_start = before
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |newline| newline + 1);
+        let column = String::from_utf8_lossy(&before[line_start..])
+            .chars()
+            .count()
+            + 1;
+        Location { byte, line, column }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {} column {} (byte {})",
+            self.line, self.column, self.byte
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// The function name in `<!--embed function ...-->` isn't registered
+    UnknownFunction(String),
+    /// An `<!--embed` comment that couldn't be parsed as a tag, along with why
+    MalformedTag(String),
+    /// An opening tag was never closed
+    MissingEndTag,
+    /// More than an opening and closing tag share an id
+    DuplicateTag(usize),
+    /// `exec-code` was used on text without a fenced code block
+    MissingCodeBlock,
+    /// The path given is neither a file nor a directory
+    InvalidPath,
+    /// A glob that couldn't be parsed, along with why
+    InvalidPattern(String, String),
+    /// Embeds that read each other's output through `$$id$$`, the ids in the order they depend on
+    /// each other starting and ending with the same id
+    DependencyCycle(Vec<String>),
+    /// A tag param that is missing or has an unusable value, along with why
+    InvalidParam(String, String),
+    /// A tag param the function doesn't have, along with the closest one it does
+    UnknownParam(String, Option<String>),
+    Io(std::io::Error),
+    /// Running an embed failed for a reason other than io
+    Exec(String),
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::UnknownFunction(name) => write!(f, "'{}' is not a known function", name),
+            ErrorKind::InvalidParam(param, reason) => {
+                write!(f, "invalid param '{}': {}", param, reason)
+            }
+            ErrorKind::UnknownParam(param, suggestion) => match suggestion {
+                Some(suggestion) => write!(
+                    f,
+                    "unknown param '{}', did you mean '{}'?",
+                    param, suggestion
+                ),
+                None => write!(f, "unknown param '{}'", param),
+            },
+            ErrorKind::MalformedTag(reason) => write!(f, "malformed tag: {}", reason),
+            ErrorKind::MissingEndTag => write!(f, "no end tag found"),
+            ErrorKind::DuplicateTag(count) => {
+                write!(
+                    f,
+                    "id has {} tags, expected an opening and closing tag",
+                    count
+                )
+            }
+            ErrorKind::DependencyCycle(ids) => {
+                write!(f, "dependency cycle: {}", ids.join(" -> "))
+            }
+            ErrorKind::MissingCodeBlock => write!(f, "no code block found"),
+            ErrorKind::InvalidPath => write!(f, "path must point to a file or directory"),
+            ErrorKind::InvalidPattern(glob, reason) => {
+                write!(f, "invalid pattern '{}': {}", glob, reason)
+            }
+            ErrorKind::Io(e) => write!(f, "{}", e),
+            ErrorKind::Exec(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// An error from processing a markdown file, carrying as much context as was known when it happened
+#[derive(Debug)]
+pub struct EmbedError {
+    /// Boxed to keep `Result<_, EmbedError>` small
+    pub kind: Box<ErrorKind>,
+    pub file: Option<PathBuf>,
+    pub id: Option<String>,
+    pub location: Option<Location>,
+}
+
+impl EmbedError {
+    pub fn new(kind: ErrorKind) -> Self {
+        EmbedError {
+            kind: Box::new(kind),
+            file: None,
+            id: None,
+            location: None,
+        }
+    }
+
+    pub fn exec(message: impl Into<String>) -> Self {
+        EmbedError::new(ErrorKind::Exec(message.into()))
+    }
+
+    /// Sets the file unless one is already set, so the innermost context wins
+    pub fn with_file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file.get_or_insert_with(|| file.into());
+        self
+    }
+
+    pub fn with_id(mut self, id: impl Into<String>) -> Self {
+        self.id.get_or_insert_with(|| id.into());
+        self
+    }
+
+    pub fn with_location(mut self, location: Location) -> Self {
+        self.location.get_or_insert(location);
+        self
+    }
+}
+
+impl Display for EmbedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}", file.display())?;
+            match &self.location {
+                Some(location) => write!(f, ":{}:{}: ", location.line, location.column)?,
+                None => write!(f, ": ")?,
+            }
+        } else if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        if let Some(id) = &self.id {
+            write!(f, "[{}] ", id)?;
+        }
+        write!(f, "{}", self.kind)
+    }
+}
+
+impl std::error::Error for EmbedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self.kind.as_ref() {
+            ErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for EmbedError {
+    fn from(e: std::io::Error) -> Self {
+        EmbedError::new(ErrorKind::Io(e))
+    }
+}
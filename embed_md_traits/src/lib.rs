@@ -1,12 +1,39 @@
-use std::clone::Clone;
+mod context;
+mod error;
+mod params;
+
 use std::fmt::Debug;
 use std::ops::Range;
 
+pub use context::{Context, OutputStore, RunMode};
+pub use error::{EmbedError, ErrorKind, Location};
+pub use params::{format_duration, parse_duration, suggest, EmbedParams, ParamHelp, ParamValue};
+#[doc(hidden)]
+pub use params::{optional_param, reject_unknown_params, required_param};
+
 pub trait Rangeable {
     fn range(&self) -> Range<usize>;
     fn id(&self) -> String;
 }
 
-pub trait FunctionTag: Rangeable + Debug + Clone {
-    fn transform(&self, text: String) -> String;
+/// The function a pair of tags runs on the text between them. Functions are shared with the
+/// threads running a document's embeds, so they must be `Send` and `Sync`.
+pub trait FunctionTag: Rangeable + Debug + Send + Sync {
+    fn transform(&self, text: String, context: &Context) -> Result<String, EmbedError>;
+
+    /// Ids of the embeds whose output this one reads, given the text between its tags. Those
+    /// embeds run first.
+    fn dependencies(&self, _text: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Absolute globs of the files this reads, watching runs it again when one changes
+    fn depends_on(&self, _context: &Context) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Why the text between the tags went from `before` to `after`, shown for stale embeds
+    fn stale_reasons(&self, _before: &str, _after: &str) -> Vec<String> {
+        Vec::new()
+    }
 }
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{EmbedError, ErrorKind};
+
+/// Params read from a tag into a typed struct, usually through `#[derive(EmbedParams)]`. Each
+/// field is a param named after it, `Option` fields may be left out and the rest need a
+/// `#[param(default = "...")]` unless they must always be given.
+pub trait EmbedParams: Sized {
+    /// Reads the params, rejecting any the struct doesn't have
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, EmbedError>;
+
+    /// Every param, in the order they are declared
+    fn help() -> Vec<ParamHelp>;
+}
+
+/// A type a single param can be read as
+pub trait ParamValue: Sized {
+    /// What the value looks like, shown in help
+    const KIND: &'static str;
+
+    fn parse_param(value: &str) -> Result<Self, String>;
+}
+
+/// A param as shown in help
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParamHelp {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub default: Option<&'static str>,
+    /// The field's doc comment
+    pub doc: &'static str,
+}
+
+impl Display for ParamHelp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}=<{}>", self.name, self.kind)?;
+        if !self.doc.is_empty() {
+            write!(f, "  {}", self.doc)?;
+        }
+        match self.default {
+            Some(default) => write!(f, " (default: {})", default),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ParamValue for String {
+    const KIND: &'static str = "text";
+
+    fn parse_param(value: &str) -> Result<Self, String> {
+        Ok(value.to_string())
+    }
+}
+
+impl ParamValue for bool {
+    const KIND: &'static str = "true|false";
+
+    fn parse_param(value: &str) -> Result<Self, String> {
+        match value {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(format!("'{}' should be true or false", value)),
+        }
+    }
+}
+
+impl ParamValue for u64 {
+    const KIND: &'static str = "number";
+
+    fn parse_param(value: &str) -> Result<Self, String> {
+        value
+            .parse()
+            .map_err(|_| format!("'{}' is not a whole number", value))
+    }
+}
+
+impl ParamValue for PathBuf {
+    const KIND: &'static str = "path";
+
+    fn parse_param(value: &str) -> Result<Self, String> {
+        Ok(PathBuf::from(value))
+    }
+}
+
+impl ParamValue for Duration {
+    const KIND: &'static str = "duration";
+
+    fn parse_param(value: &str) -> Result<Self, String> {
+        parse_duration(value)
+    }
+}
+
+/// Comma separated, with blanks around each item trimmed and empty items left out
+impl ParamValue for Vec<String> {
+    const KIND: &'static str = "a,b,...";
+
+    fn parse_param(value: &str) -> Result<Self, String> {
+        Ok(value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+/// Parses durations such as `30s`, `30m`, `1h` or `7d`, a bare number is seconds
+pub fn parse_duration(t: &str) -> Result<Duration, String> {
+    let t = t.trim();
+    let split = t.find(|c: char| !c.is_ascii_digit()).unwrap_or(t.len());
+    let (amount, unit) = t.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration like 30s, 30m, 1h or 7d", t))?;
+    let seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        _ => {
+            return Err(format!(
+                "'{}' is not a duration unit, use s, m, h or d",
+                unit
+            ))
+        }
+    };
+    amount
+        .checked_mul(seconds)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("'{}' is too long a duration", t))
+}
+
+/// Writes a duration the way [`parse_duration`] reads it, in the largest unit that keeps it whole
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    [("d", 60 * 60 * 24), ("h", 60 * 60), ("m", 60)]
+        .into_iter()
+        .find(|(_, unit)| seconds > 0 && seconds.rem_euclid(*unit) == 0)
+        .map_or(format!("{}s", seconds), |(suffix, unit)| {
+            format!("{}{}", seconds / unit, suffix)
+        })
+}
+
+/// Reads a param the struct can't do without, falling back to its default
+#[doc(hidden)]
+pub fn required_param<T: ParamValue>(
+    params: &HashMap<String, String>,
+    key: &str,
+    default: Option<&str>,
+) -> Result<T, EmbedError> {
+    match params.get(key).map(String::as_str).or(default) {
+        Some(value) => T::parse_param(value).map_err(|reason| invalid(key, reason)),
+        None => Err(invalid(key, "required".to_string())),
+    }
+}
+
+/// Reads a param that may be left out
+#[doc(hidden)]
+pub fn optional_param<T: ParamValue>(
+    params: &HashMap<String, String>,
+    key: &str,
+    default: Option<&str>,
+) -> Result<Option<T>, EmbedError> {
+    params
+        .get(key)
+        .map(String::as_str)
+        .or(default)
+        .map(|value| T::parse_param(value).map_err(|reason| invalid(key, reason)))
+        .transpose()
+}
+
+/// Fails on the first param that isn't one of `known`, suggesting the closest known one
+#[doc(hidden)]
+pub fn reject_unknown_params(
+    params: &HashMap<String, String>,
+    known: &[&str],
+) -> Result<(), EmbedError> {
+    let mut keys: Vec<&String> = params.keys().collect();
+    keys.sort();
+    match keys.into_iter().find(|key| !known.contains(&key.as_str())) {
+        Some(key) => Err(EmbedError::new(ErrorKind::UnknownParam(
+            key.clone(),
+            suggest(key, known).map(str::to_string),
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn invalid(key: &str, reason: String) -> EmbedError {
+    EmbedError::new(ErrorKind::InvalidParam(key.to_string(), reason))
+}
+
+/// The candidate closest to `word` when it is close enough to be a typo of it
+pub fn suggest<'a>(word: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let limit = (word.chars().count() / 3).max(1);
+    candidates
+        .iter()
+        .map(|candidate| (edit_distance(word, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= limit)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The number of insertions, deletions, substitutions and swaps of neighbouring characters that
+/// turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut distances = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in distances.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, distance) in distances[0].iter_mut().enumerate() {
+        *distance = j;
+    }
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (distances[i - 1][j] + 1)
+                .min(distances[i][j - 1] + 1)
+                .min(distances[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(distances[i - 2][j - 2] + 1);
+            }
+            distances[i][j] = distance;
+        }
+    }
+    distances[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("").is_err());
+        assert_eq!(
+            parse_duration("999999999999999999d"),
+            Err("'999999999999999999d' is too long a duration".to_string())
+        );
+        assert!(parse_duration("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        for duration in ["1s", "90s", "30m", "1h", "7d", "0s"] {
+            assert_eq!(format_duration(parse_duration(duration).unwrap()), duration);
+        }
+        assert_eq!(format_duration(parse_duration("120").unwrap()), "2m");
+    }
+
+    #[test]
+    fn test_suggest() {
+        let known = ["cache", "lang", "o_lang", "timeout", "ttl"];
+        assert_eq!(suggest("cahce", &known), Some("cache"));
+        assert_eq!(suggest("o_lnag", &known), Some("o_lang"));
+        assert_eq!(suggest("timeuot", &known), Some("timeout"));
+        assert_eq!(suggest("tl", &known), Some("ttl"));
+        assert_eq!(suggest("interpreter", &known), None);
+    }
+
+    #[test]
+    fn test_reject_unknown_params() {
+        let params = HashMap::from([
+            ("cache".to_string(), "hash".to_string()),
+            ("cahce".to_string(), "hash".to_string()),
+        ]);
+        assert!(reject_unknown_params(&params, &["cache", "cahce"]).is_ok());
+        let error = reject_unknown_params(&params, &["cache"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unknown param 'cahce', did you mean 'cache'?"
+        );
+    }
+
+    #[test]
+    fn test_param_values() {
+        assert_eq!(bool::parse_param("true"), Ok(true));
+        assert!(bool::parse_param("yes").is_err());
+        assert_eq!(
+            Vec::<String>::parse_param(" a.json, ,b/* "),
+            Ok(vec!["a.json".to_string(), "b/*".to_string()])
+        );
+        let params = HashMap::from([("ttl".to_string(), "soon".to_string())]);
+        let error = optional_param::<Duration>(&params, "ttl", None).unwrap_err();
+        assert!(matches!(*error.kind, ErrorKind::InvalidParam(ref p, _) if p == "ttl"));
+        assert_eq!(
+            required_param::<String>(&HashMap::new(), "lang", Some("shell")).unwrap(),
+            "shell"
+        );
+        assert!(required_param::<String>(&HashMap::new(), "lang", None).is_err());
+    }
+}